@@ -3,14 +3,16 @@ module github.com/compliance-framework/portal
 go 1.21.4
 
 require (
+	github.com/fsnotify/fsnotify v1.7.0
 	github.com/k0kubun/pp v3.0.1+incompatible
+	github.com/klauspost/compress v1.17.6
 	github.com/valyala/fasthttp v1.52.0
+	golang.org/x/crypto v0.19.0
 )
 
 require (
 	github.com/andybalholm/brotli v1.1.0 // indirect
 	github.com/k0kubun/colorstring v0.0.0-20150214042306-9440f1994b88 // indirect
-	github.com/klauspost/compress v1.17.6 // indirect
 	github.com/mattn/go-colorable v0.1.13 // indirect
 	github.com/mattn/go-isatty v0.0.20 // indirect
 	github.com/valyala/bytebufferpool v1.0.0 // indirect
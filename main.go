@@ -1,27 +1,92 @@
 package main
 
 import (
+	"archive/tar"
+	"archive/zip"
+	"bufio"
 	"bytes"
+	"cmp"
 	"compress/gzip"
+	"container/list"
+	"context"
+	"crypto"
+	"crypto/ecdsa"
+	"crypto/elliptic"
+	"crypto/hmac"
+	cryptorand "crypto/rand"
+	"crypto/rsa"
+	"crypto/sha256"
+	_ "crypto/sha512"
+	"crypto/subtle"
+	"encoding/base64"
+	"encoding/gob"
+	"encoding/hex"
 	"encoding/json"
+	"errors"
+	"flag"
 	"fmt"
+	"html"
+	"io"
+	"io/fs"
+	"math/big"
+	"math/rand"
+	"net"
 	"net/http"
+	"net/url"
 	"os"
+	"os/exec"
+	"os/signal"
+	"path"
 	"path/filepath"
+	"regexp"
+	"runtime"
+	"slices"
+	"sort"
+	"strconv"
 	"strings"
+	"sync"
+	"sync/atomic"
+	"syscall"
 	"text/template"
+	"time"
 
 	"github.com/andybalholm/brotli"
+	"github.com/fsnotify/fsnotify"
+	"github.com/klauspost/compress/zstd"
 	"github.com/valyala/fasthttp"
+	"golang.org/x/crypto/bcrypt"
 )
 
 type Route struct {
 	Content      Content
+	Lazy         *LazyContent
+	Source       string
+	Size         int64
+	ModTime      int64
+	FilePath     string
+	Hash         string
 	ContentType  string
 	LastModified string
+	// Hash of the file as read from disk, before templating, for checking it hasn't drifted
+	SourceHash string
+	// When the route was built, in unix nanoseconds
+	BuiltAt int64
+	// Extra response headers and redirect aliases from the file's sidecar metadata
+	Headers      map[string]string
+	RedirectFrom []string
+	MetaModTime  int64
+	// When the route becomes visible and gone, in unix nanoseconds, or 0 for no limit
+	PublishAt   int64
+	UnpublishAt int64
+	// Where a redirect alias points to
+	Redirect string
+	// A fixed status served instead of content, from STATUS_OVERRIDES
+	Status int
+	// The JSON listing alongside a directory's HTML autoindex
+	JsonIndex *Route
 }
 
-type Routes map[string]Route
+type Routes map[string]*Route
 
 func getEnv(name string, fallback string) string {
 	value, exists := os.LookupEnv(name)
@@ -31,23 +96,371 @@ func getEnv(name string, fallback string) string {
 	return value
 }
 
-func getAppEnv() map[string]string {
-	prefix := getEnv("CONFIG_PREFIX", "VITE_")
-	appEnv := make(map[string]string)
+// Parse a byte size with an optional unit, e.g. "512MB"
+func parseSize(size string) (int, error) {
+	size = strings.ToUpper(strings.TrimSpace(size))
+	multiplier := 1
+	for _, unit := range []struct {
+		suffix     string
+		multiplier int
+	}{{"GB", 1 << 30}, {"MB", 1 << 20}, {"KB", 1 << 10}, {"B", 1}} {
+		if strings.HasSuffix(size, unit.suffix) {
+			size = strings.TrimSpace(strings.TrimSuffix(size, unit.suffix))
+			multiplier = unit.multiplier
+			break
+		}
+	}
+	value, err := strconv.Atoi(size)
+	return value * multiplier, err
+}
+
+func getEnvSize(name string, fallback int) int {
+	value, err := parseSize(getEnv(name, strconv.Itoa(fallback)))
+	if err != nil {
+		fmt.Println("⇨ invalid value for", name+", using", fallback)
+		return fallback
+	}
+	return value
+}
+
+func getEnvFloat(name string, fallback float64) float64 {
+	value, err := strconv.ParseFloat(getEnv(name, strconv.FormatFloat(fallback, 'f', -1, 64)), 64)
+	if err != nil {
+		fmt.Println("⇨ invalid value for", name+", using", fallback)
+		return fallback
+	}
+	return value
+}
+
+func getEnvInt(name string, fallback int) int {
+	value, err := strconv.Atoi(getEnv(name, strconv.Itoa(fallback)))
+	if err != nil {
+		fmt.Println("⇨ invalid value for", name+", using", fallback)
+		return fallback
+	}
+	return value
+}
+
+func getConfigPrefixes() []string {
+	var prefixes []string
+	for _, prefix := range strings.Split(getEnv("CONFIG_PREFIX", "VITE_"), ",") {
+		prefix = strings.TrimSpace(prefix)
+		if prefix != "" {
+			prefixes = append(prefixes, prefix)
+		}
+	}
+	return prefixes
+}
+
+// Collect the env vars matching each config prefix, keyed by prefix
+func getPrefixedEnv() map[string]map[string]string {
+	prefixedEnv := make(map[string]map[string]string)
+	for _, prefix := range configPrefixes {
+		prefixedEnv[prefix] = make(map[string]string)
+	}
 	for _, env := range os.Environ() {
 		parts := strings.Split(env, "=")
 		key := parts[0]
 		value := strings.Join(parts[1:], "=")
-		if strings.HasPrefix(key, prefix) {
-			appEnv[strings.Replace(key, prefix, "", 1)] = value
+		for _, prefix := range configPrefixes {
+			if strings.HasPrefix(key, prefix) {
+				prefixedEnv[prefix][strings.Replace(key, prefix, "", 1)] = value
+			}
+		}
+	}
+	return prefixedEnv
+}
+
+// Merge the prefixed env vars into a flat map, earlier prefixes taking precedence
+func getAppEnv() map[string]string {
+	appEnv := make(map[string]string)
+	for i := len(configPrefixes) - 1; i >= 0; i-- {
+		for key, value := range prefixedEnv[configPrefixes[i]] {
+			appEnv[key] = value
 		}
 	}
 	return appEnv
 }
 
+// Load the typed defaults the env config is merged over, from CONFIG_DEFAULTS or ./config.defaults.json
+func getConfigDefaults() map[string]interface{} {
+	defaults := make(map[string]interface{})
+	path, isSet := os.LookupEnv("CONFIG_DEFAULTS")
+	if !isSet {
+		path = "config.defaults.json"
+	}
+	dat, err := os.ReadFile(path)
+	if err != nil {
+		if !isSet && errors.Is(err, os.ErrNotExist) {
+			return defaults
+		}
+		fmt.Println("⇨ error reading config defaults", err)
+		os.Exit(-1)
+	}
+	if err := json.Unmarshal(dat, &defaults); err != nil {
+		fmt.Println("⇨ error parsing config defaults", err)
+		os.Exit(-1)
+	}
+	return defaults
+}
+
+// Coerce an env value to the type of its default, so e.g. "false" stays a boolean
+func coerceConfigValue(value string, fallback interface{}) interface{} {
+	switch fallback.(type) {
+	case bool:
+		if parsed, err := strconv.ParseBool(value); err == nil {
+			return parsed
+		}
+	case float64:
+		if parsed, err := strconv.ParseFloat(value, 64); err == nil {
+			return parsed
+		}
+	}
+	return value
+}
+
+// The defaults with env values merged over them
+func getConfig() map[string]interface{} {
+	config := make(map[string]interface{})
+	for key, value := range configDefaults {
+		config[key] = value
+	}
+	for key, value := range appEnv {
+		config[key] = coerceConfigValue(value, configDefaults[key])
+	}
+	return config
+}
+
+// The flat config plus a namespace per prefix, so both {{.Env.API_URL}} and {{.Env.VITE.API_URL}} work
+func getTemplateEnv() map[string]interface{} {
+	templateEnv := make(map[string]interface{})
+	for key, value := range config {
+		templateEnv[key] = value
+	}
+	for prefix, env := range prefixedEnv {
+		templateEnv[strings.TrimSuffix(prefix, "_")] = env
+	}
+	return templateEnv
+}
+
+type SchemaProperty struct {
+	Type      string        `json:"type"`
+	Pattern   string        `json:"pattern"`
+	Enum      []interface{} `json:"enum"`
+	MinLength *int          `json:"minLength"`
+	MaxLength *int          `json:"maxLength"`
+	Minimum   *float64      `json:"minimum"`
+	Maximum   *float64      `json:"maximum"`
+}
+
+type ConfigSchema struct {
+	Required             []string                  `json:"required"`
+	Properties           map[string]SchemaProperty `json:"properties"`
+	AdditionalProperties *bool                     `json:"additionalProperties"`
+}
+
+func validateConfigValue(key string, value string, property SchemaProperty) []string {
+	var problems []string
+	switch property.Type {
+	case "number", "integer":
+		number, err := strconv.ParseFloat(value, 64)
+		if err != nil || (property.Type == "integer" && number != float64(int64(number))) {
+			problems = append(problems, key+" is not a valid "+property.Type)
+			break
+		}
+		if property.Minimum != nil && number < *property.Minimum {
+			problems = append(problems, fmt.Sprintf("%s is less than %v", key, *property.Minimum))
+		}
+		if property.Maximum != nil && number > *property.Maximum {
+			problems = append(problems, fmt.Sprintf("%s is greater than %v", key, *property.Maximum))
+		}
+	case "boolean":
+		if value != "true" && value != "false" {
+			problems = append(problems, key+" is not a valid boolean")
+		}
+	}
+	if property.MinLength != nil && len(value) < *property.MinLength {
+		problems = append(problems, fmt.Sprintf("%s is shorter than %d characters", key, *property.MinLength))
+	}
+	if property.MaxLength != nil && len(value) > *property.MaxLength {
+		problems = append(problems, fmt.Sprintf("%s is longer than %d characters", key, *property.MaxLength))
+	}
+	if property.Pattern != "" {
+		pattern, err := regexp.Compile(property.Pattern)
+		if err != nil {
+			problems = append(problems, key+" has an invalid pattern in the schema")
+		} else if !pattern.MatchString(value) {
+			problems = append(problems, key+" does not match "+property.Pattern)
+		}
+	}
+	if property.Enum != nil {
+		allowed := false
+		for _, option := range property.Enum {
+			if fmt.Sprint(option) == value {
+				allowed = true
+			}
+		}
+		if !allowed {
+			problems = append(problems, fmt.Sprintf("%s is not one of %v", key, property.Enum))
+		}
+	}
+	return problems
+}
+
+// Validate the collected env against a (subset of) JSON schema, keyed by unprefixed name
+func validateConfig(schema ConfigSchema, env map[string]string) []string {
+	var problems []string
+	for _, key := range schema.Required {
+		if _, exists := env[key]; !exists {
+			problems = append(problems, key+" is required")
+		}
+	}
+	for key, value := range env {
+		property, exists := schema.Properties[key]
+		if !exists {
+			if schema.AdditionalProperties != nil && !*schema.AdditionalProperties {
+				problems = append(problems, key+" is not allowed")
+			}
+			continue
+		}
+		problems = append(problems, validateConfigValue(key, value, property)...)
+	}
+	return problems
+}
+
+// Check the runtime config against CONFIG_SCHEMA, if set, before serving anything
+func checkConfigSchema() {
+	schemaPath := getEnv("CONFIG_SCHEMA", "")
+	if schemaPath == "" {
+		return
+	}
+	dat, err := os.ReadFile(schemaPath)
+	if err != nil {
+		fmt.Println("⇨ error reading config schema", err)
+		os.Exit(-1)
+	}
+	var schema ConfigSchema
+	if err := json.Unmarshal(dat, &schema); err != nil {
+		fmt.Println("⇨ error parsing config schema", err)
+		os.Exit(-1)
+	}
+	env := make(map[string]string)
+	for key, value := range config {
+		env[key] = fmt.Sprint(value)
+	}
+	problems := validateConfig(schema, env)
+	for _, problem := range problems {
+		fmt.Println("⇨ invalid config:", problem)
+	}
+	if len(problems) > 0 && getEnv("CONFIG_SCHEMA_MODE", "fail") != "warn" {
+		os.Exit(-1)
+	}
+}
+
+type Glob struct {
+	Pattern  *regexp.Regexp
+	FullPath bool
+}
+
+type Globs []Glob
+
+// Compile a glob into a regexp, where ** matches across directories and * and ? don't
+func compileGlob(pattern string) (*regexp.Regexp, error) {
+	var b strings.Builder
+	b.WriteString("^")
+	for i := 0; i < len(pattern); i++ {
+		switch pattern[i] {
+		case '*':
+			if i+1 < len(pattern) && pattern[i+1] == '*' {
+				b.WriteString(".*")
+				i++
+			} else {
+				b.WriteString("[^/]*")
+			}
+		case '?':
+			b.WriteString("[^/]")
+		default:
+			b.WriteString(regexp.QuoteMeta(pattern[i : i+1]))
+		}
+	}
+	b.WriteString("$")
+	return regexp.Compile(b.String())
+}
+
+func parseGlobs(patterns []string) (Globs, error) {
+	var globs Globs
+	for _, pattern := range patterns {
+		pattern = strings.TrimSpace(pattern)
+		if pattern == "" {
+			continue
+		}
+		compiled, err := compileGlob(strings.TrimPrefix(pattern, "/"))
+		if err != nil {
+			return nil, fmt.Errorf("invalid pattern %s", pattern)
+		}
+		globs = append(globs, Glob{Pattern: compiled, FullPath: strings.Contains(pattern, "/")})
+	}
+	return globs, nil
+}
+
+// Parse a comma separated list of globs from the environment, e.g. "*.map,downloads/**"
+func getGlobs(name string, fallback string) Globs {
+	globs, err := parseGlobs(strings.Split(getEnv(name, fallback), ","))
+	if err != nil {
+		fmt.Println("⇨", err, "in", name)
+		os.Exit(-1)
+	}
+	return globs
+}
+
+// Patterns containing a slash match the path from the public dir root, others match the basename
+func (globs Globs) Match(urlPath string) bool {
+	relPath := strings.TrimPrefix(urlPath, "/")
+	baseName := relPath[strings.LastIndex(relPath, "/")+1:]
+	for _, glob := range globs {
+		if glob.FullPath && glob.Pattern.MatchString(relPath) {
+			return true
+		}
+		if !glob.FullPath && glob.Pattern.MatchString(baseName) {
+			return true
+		}
+	}
+	return false
+}
+
+type Encodings map[string]bool
+
+// Parse the comma separated list of encodings to precompress, e.g. "br,gzip"
+func getEncodings() Encodings {
+	encodings := make(Encodings)
+	if getEnv("NO_COMPRESS", "0") == "1" {
+		return encodings
+	}
+	for _, encoding := range strings.Split(getEnv("ENCODINGS", "br,gzip"), ",") {
+		encoding = strings.TrimSpace(encoding)
+		if encoding != "" {
+			encodings[encoding] = true
+		}
+	}
+	return encodings
+}
+
+var configPrefixes = getConfigPrefixes()
+var prefixedEnv = getPrefixedEnv()
 var appEnv = getAppEnv()
-var publicDir = getEnv("PUBLIC_DIR", "public")
-var routes Routes = make(map[string]Route)
+var configDefaults = getConfigDefaults()
+var config = getConfig()
+var templateEnv = getTemplateEnv()
+var encodings = getEncodings()
+var compressMinSize = getEnvInt("COMPRESS_MIN_SIZE", 1024)
+var compressExclude = getGlobs("COMPRESS_EXCLUDE", "")
+var publicDirs = getPublicDirs()
+var routes Routes = make(map[string]*Route)
+var routesLock sync.RWMutex
+var assets = make(map[string]string)
+var assetsLock sync.RWMutex
+var populated atomic.Bool
+var lazyPopulate = getEnv("LAZY_POPULATE", "0") == "1"
 
 func getMimetype(ext string) string {
 	switch ext {
@@ -65,6 +478,22 @@ func getMimetype(ext string) string {
 		return "application/pdf"
 	case ".zip":
 		return "application/zip"
+	// Already compressed, so served as-is without a Content-Encoding, which would make clients
+	// decompress the download
+	case ".gz", ".tgz":
+		return "application/gzip"
+	case ".br":
+		return "application/x-brotli"
+	case ".bz2":
+		return "application/x-bzip2"
+	case ".xz":
+		return "application/x-xz"
+	case ".zst":
+		return "application/zstd"
+	case ".7z":
+		return "application/x-7z-compressed"
+	case ".tar":
+		return "application/x-tar"
 	case ".doc":
 		return "application/msword"
 	case ".eot":
@@ -111,9 +540,15 @@ func getMimetype(ext string) string {
 }
 
 type TemplateData struct {
-	Env         map[string]string `json:"env"`
-	Json        string            `json:"json"`
-	EscapedJson string            `json:"escapedJson"`
+	Env map[string]interface{} `json:"env"`
+	// JSON with <, > and & escaped, so it is safe to embed in a script tag
+	Json string `json:"json"`
+	// The JSON as a quoted JS string literal, e.g. window.RUNTIME_ENV = {{.JsString}};
+	JsString string `json:"jsString"`
+	// The JSON escaped for use inside a double quoted HTML attribute
+	HtmlAttr string `json:"htmlAttr"`
+	// Deprecated: only escapes double quotes, which breaks on backslashes and newlines. Use JsString.
+	EscapedJson string `json:"escapedJson"`
 }
 
 type Content struct {
@@ -122,19 +557,284 @@ type Content struct {
 	Brotli []byte
 }
 
-func templateRoute(name string, content string) (string, error) {
+type cacheEntry struct {
+	key     string
+	content Content
+	size    int
+}
+
+// An LRU of route bodies bounded by MAX_CACHE_MEMORY, keyed by source file. A nil cache, for a
+// budget of 0, holds nothing.
+type BodyCache struct {
+	lock    sync.Mutex
+	budget  int
+	size    int
+	entries map[string]*list.Element
+	order   *list.List
+}
+
+func newBodyCache(budget int) *BodyCache {
+	if budget <= 0 {
+		return nil
+	}
+	return &BodyCache{
+		budget:  budget,
+		entries: make(map[string]*list.Element),
+		order:   list.New(),
+	}
+}
+
+func (cache *BodyCache) Get(key string) (Content, bool) {
+	if cache == nil {
+		return Content{}, false
+	}
+	cache.lock.Lock()
+	defer cache.lock.Unlock()
+	element, exists := cache.entries[key]
+	if !exists {
+		return Content{}, false
+	}
+	cache.order.MoveToFront(element)
+	return element.Value.(*cacheEntry).content, true
+}
+
+func (cache *BodyCache) Clear() {
+	if cache == nil {
+		return
+	}
+	cache.lock.Lock()
+	defer cache.lock.Unlock()
+	cache.entries = make(map[string]*list.Element)
+	cache.order.Init()
+	cache.size = 0
+}
+
+// Bodies larger than the whole budget are never cached
+func (cache *BodyCache) Put(key string, content Content) {
+	if cache == nil {
+		return
+	}
+	cache.lock.Lock()
+	defer cache.lock.Unlock()
+	if element, exists := cache.entries[key]; exists {
+		cache.size -= element.Value.(*cacheEntry).size
+		cache.order.Remove(element)
+		delete(cache.entries, key)
+	}
+	size := len(content.Plain) + len(content.Gzip) + len(content.Brotli)
+	if size > cache.budget {
+		return
+	}
+	cache.entries[key] = cache.order.PushFront(&cacheEntry{key: key, content: content, size: size})
+	cache.size += size
+	for cache.size > cache.budget {
+		oldest := cache.order.Back()
+		entry := oldest.Value.(*cacheEntry)
+		cache.order.Remove(oldest)
+		delete(cache.entries, entry.key)
+		cache.size -= entry.size
+	}
+}
+
+// Compressed variants built on first request for that encoding, for LAZY_COMPRESS
+type LazyContent struct {
+	lock     sync.Mutex
+	variants map[string][]byte
+}
+
+// Holding the lock while compressing means concurrent requests wait for one compression
+func (lazy *LazyContent) Get(encoding string, plain []byte) []byte {
+	lazy.lock.Lock()
+	defer lazy.lock.Unlock()
+	if variant, exists := lazy.variants[encoding]; exists {
+		return variant
+	}
+	variant := compressVariant(encoding, plain)
+	lazy.variants[encoding] = variant
+	return variant
+}
+
+func compressVariant(encoding string, plain []byte) []byte {
+	switch encoding {
+	case "br":
+		return brotliData(plain, defaultLevels.Brotli)
+	case "gzip":
+		return gzipData(plain, defaultLevels.Gzip)
+	}
+	return nil
+}
+
+// With a body cache, lazy variants are stored in the body's cache entry instead, so they count
+// against MAX_CACHE_MEMORY and are dropped along with the body they were compressed from. The
+// route's LazyContent lock still makes concurrent requests wait for one compression.
+func getCachedVariant(route *Route, content Content, encoding string) []byte {
+	if encoding == "br" && content.Brotli != nil {
+		return content.Brotli
+	}
+	if encoding == "gzip" && content.Gzip != nil {
+		return content.Gzip
+	}
+	route.Lazy.lock.Lock()
+	defer route.Lazy.lock.Unlock()
+	// Another request may have stored the variant, or a rebuild a newer body, while this one waited
+	if current, exists := bodyCache.Get(route.Source); exists {
+		content = current
+	}
+	switch encoding {
+	case "br":
+		if content.Brotli == nil {
+			content.Brotli = compressVariant(encoding, content.Plain)
+			bodyCache.Put(route.Source, content)
+		}
+		return content.Brotli
+	case "gzip":
+		if content.Gzip == nil {
+			content.Gzip = compressVariant(encoding, content.Plain)
+			bodyCache.Put(route.Source, content)
+		}
+		return content.Gzip
+	}
+	return nil
+}
+
+// Matches bundler style fingerprinted filenames, e.g. app.3f2a9c1d.js or index-BbQ8a_3X.js
+var fingerprintPattern = regexp.MustCompile(`^(.+)[.-]([A-Za-z0-9_-]*[0-9][A-Za-z0-9_-]*)(\.[A-Za-z0-9]+)$`)
+
+type missingAssetError struct {
+	name string
+}
+
+func (e *missingAssetError) Error() string {
+	return "asset not found: " + e.name
+}
+
+// Map asset names to url paths, with fingerprinted files also reachable by their
+// logical name (full path and basename), e.g. "/assets/app.js" → "/assets/app.3f2a9c1d.js"
+func getAssets(urlPaths []string) map[string]string {
+	assets := make(map[string]string)
+	for _, urlPath := range urlPaths {
+		assets[urlPath] = urlPath
+	}
+	for _, urlPath := range urlPaths {
+		dir, name := filepath.Split(urlPath)
+		match := fingerprintPattern.FindStringSubmatch(name)
+		if match == nil || len(match[2]) < 8 {
+			continue
+		}
+		for _, logicalName := range []string{dir + match[1] + match[3], match[1] + match[3]} {
+			if _, exists := assets[logicalName]; !exists {
+				assets[logicalName] = urlPath
+			}
+		}
+	}
+	return assets
+}
+
+// Resolve an asset name to its (fingerprinted) url path, for use as {{asset "app.js"}}
+func resolveAsset(assets map[string]string, name string) (string, error) {
+	for _, key := range []string{name, "/" + name} {
+		if urlPath, exists := assets[key]; exists {
+			return urlPath, nil
+		}
+	}
+	return "", &missingAssetError{name}
+}
+
+const envLoaderPath = "/_nano-web/env.js"
+const envLoader = `window.RUNTIME_ENV = JSON.parse(document.getElementById("env").textContent);
+`
+
+// Inject the config as a JSON script tag plus an external loader, so it works under a
+// Content-Security-Policy without 'unsafe-inline', for use as {{envScript}}
+func envScript() (string, error) {
+	jsonString, err := json.Marshal(config)
+	if err != nil {
+		return "", err
+	}
+	return `<script type="application/json" id="env">` + string(jsonString) + `</script>` +
+		`<script src="` + envLoaderPath + `"></script>`, nil
+}
+
+var templateFuncs = template.FuncMap{
+	"envScript": envScript,
+}
+
+const templateMarker = "nano-web:template"
+const noTemplateMarker = "nano-web:no-template"
+const rawStart = "<!-- nano-web:raw -->"
+const rawEnd = "<!-- /nano-web:raw -->"
+
+var templateMode = getEnv("TEMPLATE_MODE", "all")
+var templateExt = getEnv("TEMPLATE_EXT", "")
+
+// Whether a file should be templated, per TEMPLATE_MODE, TEMPLATE_EXT and the opt-out marker
+func shouldTemplate(path string, content string) bool {
+	if strings.Contains(content, noTemplateMarker) {
+		return false
+	}
+	if templateExt != "" && !strings.HasSuffix(path, templateExt) {
+		return false
+	}
+	if templateMode == "marker" {
+		return strings.Contains(content, templateMarker)
+	}
+	return true
+}
+
+// Swap raw blocks for placeholders so their contents pass through templating untouched
+func extractRawBlocks(content string) (string, []string) {
+	var raws []string
+	var b strings.Builder
+	for {
+		start := strings.Index(content, rawStart)
+		if start == -1 {
+			break
+		}
+		end := strings.Index(content[start:], rawEnd)
+		if end == -1 {
+			break
+		}
+		end += start
+		b.WriteString(content[:start])
+		fmt.Fprintf(&b, "{{raw %d}}", len(raws))
+		raws = append(raws, content[start+len(rawStart):end])
+		content = content[end+len(rawEnd):]
+	}
+	b.WriteString(content)
+	return b.String(), raws
+}
+
+func templateRoute(name string, content string, assets map[string]string) (string, error) {
 	writer := bytes.NewBufferString("")
-	tmpl, err := template.New(name).Parse(content)
+	content, raws := extractRawBlocks(content)
+	rawFuncs := template.FuncMap{
+		"raw": func(i int) string {
+			return raws[i]
+		},
+		"asset": func(name string) (string, error) {
+			return resolveAsset(assets, name)
+		},
+	}
+	tmpl, err := template.New(name).Funcs(templateFuncs).Funcs(rawFuncs).Parse(content)
 	if err != nil {
 		return "", err
 	}
-	jsonString, err := json.Marshal(appEnv)
+	jsonString, err := json.Marshal(config)
 	if err != nil {
 		return "", err
 	}
+	jsString, err := json.Marshal(string(jsonString))
+	if err != nil {
+		return "", err
+	}
+	if strings.Contains(content, ".EscapedJson") {
+		fmt.Println("⇨ warning:", name, "uses deprecated EscapedJson, use JsString instead")
+	}
 	err = tmpl.Execute(writer, &TemplateData{
-		Env:         appEnv,
+		Env:         templateEnv,
 		Json:        string(jsonString),
+		JsString:    string(jsString),
+		HtmlAttr:    html.EscapeString(string(jsonString)),
 		EscapedJson: strings.Replace(string(jsonString), "\"", "\\\"", -1),
 	})
 	if err != nil {
@@ -161,166 +861,5546 @@ func compressedType(mimetype string) bool {
 	}
 }
 
-func gzipData(dat []byte) []byte {
+type CompressionLevels struct {
+	Gzip   int
+	Brotli int
+}
+
+var defaultLevels = CompressionLevels{Gzip: gzip.DefaultCompression, Brotli: brotli.DefaultCompression}
+var fastLevels = CompressionLevels{Gzip: gzip.BestSpeed, Brotli: brotli.BestSpeed}
+var bestLevels = CompressionLevels{Gzip: gzip.BestCompression, Brotli: brotli.BestCompression}
+
+var backgroundRecompress = getEnv("BACKGROUND_RECOMPRESS", "0") == "1"
+var lazyCompress = getEnv("LAZY_COMPRESS", "0") == "1"
+var maxCacheFileSize = getEnvSize("MAX_CACHE_FILE_SIZE", 0)
+var bodyCache = newBodyCache(getEnvSize("MAX_CACHE_MEMORY", 0))
+
+// With background recompression we compress fast to start serving immediately
+func getStartupLevels() CompressionLevels {
+	if backgroundRecompress {
+		return fastLevels
+	}
+	return defaultLevels
+}
+
+func gzipData(dat []byte, level int) []byte {
 	var b bytes.Buffer
-	w := gzip.NewWriter(&b)
+	w, err := gzip.NewWriterLevel(&b, level)
+	if err != nil {
+		w = gzip.NewWriter(&b)
+	}
 	w.Write(dat)
 	w.Close()
 	return b.Bytes()
 }
 
-func brotliData(dat []byte) []byte {
+func brotliData(dat []byte, level int) []byte {
 	var b bytes.Buffer
-	w := brotli.NewWriter(&b)
+	w := brotli.NewWriterLevel(&b, level)
 	w.Write(dat)
 	w.Close()
 	return b.Bytes()
 
 }
 
-func makeRoute(path string) (Route, error) {
-	ext := strings.ToLower(path[strings.LastIndex(path, "."):])
-	mimetype := getMimetype(ext)
-	dat, err := os.ReadFile(path)
-
-	if err != nil {
-		return Route{}, err
+func compressContent(dat []byte, encodings Encodings, levels CompressionLevels) Content {
+	content := Content{
+		Plain: dat,
 	}
+	if encodings["gzip"] {
+		content.Gzip = gzipData(dat, levels.Gzip)
+	}
+	if encodings["br"] {
+		content.Brotli = brotliData(dat, levels.Brotli)
+	}
+	return content
+}
 
-	info, err := os.Stat(path)
+const metaSuffix = ".meta.json"
 
-	if err != nil {
-		return Route{}, err
-	}
+// Per-file overrides from an optional sidecar, e.g. about.html.meta.json
+type RouteMeta struct {
+	Headers      map[string]string `json:"headers"`
+	CacheControl string            `json:"cache_control"`
+	Template     *bool             `json:"template"`
+	RedirectFrom []string          `json:"redirect_from"`
+	PublishAt    string            `json:"publish_at"`
+	UnpublishAt  string            `json:"unpublish_at"`
+	DeprecatedAt string            `json:"deprecated_at"`
+}
 
-	if templateType(mimetype) {
-		content, err := templateRoute(path, string(dat))
+// Parse the RFC 3339 publish_at and unpublish_at times, as unix nanoseconds or 0 when unset, and
+// announce the removal in advance with Sunset and Deprecation headers
+func (meta *RouteMeta) schedule() (int64, int64, error) {
+	var times [3]int64
+	for i, value := range []string{meta.PublishAt, meta.UnpublishAt, meta.DeprecatedAt} {
+		if value == "" {
+			continue
+		}
+		at, err := time.Parse(time.RFC3339, value)
 		if err != nil {
-			return Route{}, err
+			return 0, 0, fmt.Errorf("invalid schedule time %q: %w", value, err)
 		}
-		dat = []byte(content)
-
+		times[i] = at.UnixNano()
 	}
-
-	content := Content{
-		Plain: dat,
+	if meta.Headers == nil {
+		meta.Headers = make(map[string]string)
 	}
-
-	if compressedType(mimetype) {
-		content.Gzip = gzipData(dat)
-		content.Brotli = brotliData(dat)
+	if times[1] != 0 && meta.Headers["Sunset"] == "" {
+		meta.Headers["Sunset"] = time.Unix(0, times[1]).UTC().Format(http.TimeFormat)
+	}
+	if times[2] != 0 && meta.Headers["Deprecation"] == "" {
+		meta.Headers["Deprecation"] = "@" + strconv.FormatInt(times[2]/int64(time.Second), 10)
 	}
+	return times[0], times[1], nil
+}
 
-	return Route{
-		Content:      content,
-		ContentType:  mimetype,
-		LastModified: info.ModTime().Format(http.TimeFormat),
-	}, nil
+// Modification time of a file's sidecar metadata, or 0 without one
+func metaModTime(path string) int64 {
+	info, err := os.Stat(path + metaSuffix)
+	if err != nil {
+		return 0
+	}
+	return info.ModTime().UnixNano()
 }
 
-// Walk the public dir and create routes for each file
-func populateRoutes(routes Routes) {
-	_, err := os.Stat(publicDir)
+func readRouteMeta(path string) (RouteMeta, error) {
+	var meta RouteMeta
+	dat, err := os.ReadFile(path + metaSuffix)
+	if errors.Is(err, os.ErrNotExist) {
+		return meta, nil
+	}
 	if err != nil {
-		cwd, err := os.Getwd()
-		if err != nil {
-			fmt.Println("⇨ error getting current working directory", err)
-			os.Exit(-1)
+		return meta, err
+	}
+	if err := json.Unmarshal(dat, &meta); err != nil {
+		return meta, fmt.Errorf("invalid %s: %w", path+metaSuffix, err)
+	}
+	if meta.CacheControl != "" {
+		if meta.Headers == nil {
+			meta.Headers = make(map[string]string)
 		}
-		fmt.Println("⇨ public directory not found in: " + cwd)
-		os.Exit(-1)
+		meta.Headers["Cache-Control"] = meta.CacheControl
 	}
-	filepath.Walk("public", func(path string, info os.FileInfo, err error) error {
-		if info.IsDir() {
+	return meta, nil
+}
+
+const layoutContentMarker = "<!-- nano-web:content -->"
+
+// Split "---" delimited front matter of "key: value" lines, with values either single or
+// lists ("[a, b]" or following "- a" lines), from the start of a file
+func parseFrontMatter(dat []byte) (map[string][]string, []byte) {
+	content := strings.ReplaceAll(string(dat), "\r\n", "\n")
+	if !strings.HasPrefix(content, "---\n") {
+		return nil, dat
+	}
+	end := strings.Index(content[4:], "\n---\n")
+	if end == -1 {
+		return nil, dat
+	}
+	frontMatter := make(map[string][]string)
+	key := ""
+	for _, line := range strings.Split(content[4:4+end], "\n") {
+		trimmed := strings.TrimSpace(line)
+		if strings.HasPrefix(trimmed, "- ") && key != "" {
+			frontMatter[key] = append(frontMatter[key], unquote(strings.TrimPrefix(trimmed, "- ")))
+			continue
+		}
+		name, value, found := strings.Cut(line, ":")
+		if !found {
+			continue
+		}
+		key, value = strings.TrimSpace(name), strings.TrimSpace(value)
+		if strings.HasPrefix(value, "[") && strings.HasSuffix(value, "]") {
+			frontMatter[key] = []string{}
+			for _, item := range strings.Split(value[1:len(value)-1], ",") {
+				if item = strings.TrimSpace(item); item != "" {
+					frontMatter[key] = append(frontMatter[key], unquote(item))
+				}
+			}
+		} else if value != "" {
+			frontMatter[key] = []string{unquote(value)}
+		}
+	}
+	return frontMatter, []byte(content[4+end+5:])
+}
+
+func unquote(value string) string {
+	if len(value) >= 2 && (value[0] == '"' || value[0] == '\'') && value[len(value)-1] == value[0] {
+		return value[1 : len(value)-1]
+	}
+	return value
+}
+
+// Apply front matter keys to a route's metadata, with the sidecar taking precedence, and wrap
+// the content in its layout
+func applyFrontMatter(frontMatter map[string][]string, dat []byte, meta *RouteMeta) ([]byte, error) {
+	if meta.Headers == nil {
+		meta.Headers = make(map[string]string)
+	}
+	if values := frontMatter["cache_control"]; len(values) > 0 && meta.Headers["Cache-Control"] == "" {
+		meta.Headers["Cache-Control"] = values[0]
+	}
+	if values := frontMatter["noindex"]; len(values) > 0 && values[0] == "true" && meta.Headers["X-Robots-Tag"] == "" {
+		meta.Headers["X-Robots-Tag"] = "noindex"
+	}
+	meta.RedirectFrom = append(meta.RedirectFrom, frontMatter["redirect_from"]...)
+	if values := frontMatter["publish_at"]; len(values) > 0 && meta.PublishAt == "" {
+		meta.PublishAt = values[0]
+	}
+	if values := frontMatter["unpublish_at"]; len(values) > 0 && meta.UnpublishAt == "" {
+		meta.UnpublishAt = values[0]
+	}
+	if values := frontMatter["deprecated_at"]; len(values) > 0 && meta.DeprecatedAt == "" {
+		meta.DeprecatedAt = values[0]
+	}
+	if values := frontMatter["layout"]; len(values) > 0 {
+		layout, err := os.ReadFile(findPublicFile(values[0]))
+		if err != nil {
+			return nil, fmt.Errorf("layout %s: %w", values[0], err)
+		}
+		dat = bytes.Replace(layout, []byte(layoutContentMarker), dat, 1)
+	}
+	return dat, nil
+}
+
+func makeRoute(path string) (*Route, error) {
+	ext := strings.ToLower(path[strings.LastIndex(path, "."):])
+	mimetype := getMimetype(ext)
+	info, err := os.Stat(path)
+
+	if err != nil {
+		return nil, err
+	}
+
+	meta, err := readRouteMeta(path)
+	if err != nil {
+		return nil, err
+	}
+
+	// Too large to hold in memory, so stream it from disk on each request instead
+	if maxCacheFileSize > 0 && info.Size() > int64(maxCacheFileSize) {
+		publishAt, unpublishAt, err := meta.schedule()
+		if err != nil {
+			return nil, err
+		}
+		return &Route{
+			Source:       path,
+			Size:         info.Size(),
+			ModTime:      info.ModTime().UnixNano(),
+			FilePath:     path,
+			Hash:         fmt.Sprintf("%x-%x", info.Size(), info.ModTime().UnixNano()),
+			ContentType:  mimetype,
+			LastModified: info.ModTime().Format(http.TimeFormat),
+			Headers:      meta.Headers,
+			RedirectFrom: meta.RedirectFrom,
+			MetaModTime:  metaModTime(path),
+			PublishAt:    publishAt,
+			UnpublishAt:  unpublishAt,
+			BuiltAt:      time.Now().UnixNano(),
+		}, nil
+	}
+
+	dat, err := os.ReadFile(path)
+
+	if err != nil {
+		return nil, err
+	}
+	sourceHash := fmt.Sprintf("%x", sha256.Sum256(dat))
+
+	if mimetype == "text/html" {
+		if frontMatter, body := parseFrontMatter(dat); frontMatter != nil {
+			dat, err = applyFrontMatter(frontMatter, body, &meta)
+			if err != nil {
+				return nil, err
+			}
+		}
+	}
+
+	templated := templateType(mimetype) && shouldTemplate(path, string(dat))
+	if meta.Template != nil {
+		templated = templateType(mimetype) && *meta.Template
+	}
+	if templated {
+		assetsLock.RLock()
+		current := assets
+		assetsLock.RUnlock()
+		content, err := templateRoute(path, string(dat), current)
+		var assetErr *missingAssetError
+		if errors.As(err, &assetErr) {
+			return nil, err
+		}
+		// Content that merely looks like a template (Vue, handlebars docs...) is served as-is
+		if err != nil {
+			fmt.Println("⇨ warning: serving", path, "untemplated:", err)
+		} else {
+			dat = []byte(content)
+		}
+	}
+
+	publishAt, unpublishAt, err := meta.schedule()
+	if err != nil {
+		return nil, err
+	}
+
+	if devMode && mimetype == "text/html" {
+		dat = injectLiveReload(dat)
+	}
+
+	content := Content{
+		Plain: dat,
+	}
+
+	var lazy *LazyContent
+
+	if compressedType(mimetype) && len(dat) >= compressMinSize && !compressExclude.Match(getUrlPath(path)) {
+		if lazyCompress {
+			lazy = &LazyContent{variants: make(map[string][]byte)}
+		} else {
+			content = compressContent(dat, encodings, getStartupLevels())
+		}
+	}
+
+	return &Route{
+		Content:      content,
+		Lazy:         lazy,
+		Source:       path,
+		Size:         info.Size(),
+		ModTime:      info.ModTime().UnixNano(),
+		Hash:         fmt.Sprintf("%x", sha256.Sum256(dat)),
+		ContentType:  mimetype,
+		LastModified: info.ModTime().Format(http.TimeFormat),
+		SourceHash:   sourceHash,
+		Headers:      meta.Headers,
+		RedirectFrom: meta.RedirectFrom,
+		MetaModTime:  metaModTime(path),
+		PublishAt:    publishAt,
+		UnpublishAt:  unpublishAt,
+		BuiltAt:      time.Now().UnixNano(),
+	}, nil
+}
+
+// The status served instead of a route outside its schedule: 404 before it's published, and
+// 410 once it's unpublished
+func (route *Route) scheduledStatus(now time.Time) int {
+	switch {
+	case route.PublishAt != 0 && now.UnixNano() < route.PublishAt:
+		return fasthttp.StatusNotFound
+	case route.UnpublishAt != 0 && now.UnixNano() >= route.UnpublishAt:
+		return fasthttp.StatusGone
+	}
+	return 0
+}
+
+// The first publish or unpublish time of any route after a time
+func nextScheduleChange(after time.Time) (time.Time, bool) {
+	routesLock.RLock()
+	defer routesLock.RUnlock()
+	var next int64
+	for _, route := range routes {
+		for _, at := range []int64{route.PublishAt, route.UnpublishAt} {
+			if at > after.UnixNano() && (next == 0 || at < next) {
+				next = at
+			}
+		}
+	}
+	return time.Unix(0, next), next != 0
+}
+
+// Reindex as scheduled routes are published and unpublished, so directory listings keep up with
+// them. Routes added since the last check are picked up within a minute.
+func scheduleRoutes() {
+	last := time.Now()
+	for {
+		wait := time.Minute
+		if next, exists := nextScheduleChange(last); exists {
+			wait = min(wait, time.Until(next))
+		}
+		time.Sleep(wait)
+		now := time.Now()
+		if next, exists := nextScheduleChange(last); exists && !next.After(now) && !productionMode {
+			fmt.Println("⇨ scheduled content changed, reindexing")
+			reindexRoutes(false)
+		}
+		last = now
+	}
+}
+
+var serveHidden = getEnv("SERVE_HIDDEN", "0") == "1"
+
+// Whether a url path has a dotfile or dotdir in it, other than /.well-known/, which is always served
+func isHidden(urlPath string) bool {
+	if serveHidden {
+		return false
+	}
+	for _, segment := range strings.Split(urlPath, "/") {
+		if strings.HasPrefix(segment, ".") && segment != ".well-known" {
+			return true
+		}
+	}
+	return false
+}
+
+// Comma separated PUBLIC_DIR layered in priority order, where the first dir containing a path wins,
+// e.g. "dist,common-assets"
+func getPublicDirs() []string {
+	var dirs []string
+	for _, dir := range strings.Split(getEnv("PUBLIC_DIR", "public"), ",") {
+		if dir = strings.TrimSpace(dir); dir != "" {
+			dirs = append(dirs, dir)
+		}
+	}
+	if len(dirs) == 0 {
+		dirs = []string{"public"}
+	}
+	return dirs
+}
+
+// The public dir a path sits in, or "" if it's in none of them
+func publicRoot(path string) string {
+	for _, dir := range publicDirs {
+		rel, err := filepath.Rel(dir, path)
+		if err == nil && rel != ".." && !strings.HasPrefix(rel, ".."+string(filepath.Separator)) {
+			return dir
+		}
+	}
+	return ""
+}
+
+// The path of a url path in the first public dir that has it, or in the first dir if none do
+func findPublicFile(urlPath string) string {
+	relPath := filepath.FromSlash(filepath.Clean("/" + urlPath))
+	for _, dir := range publicDirs {
+		path := filepath.Join(dir, relPath)
+		if _, err := os.Lstat(path); err == nil {
+			return path
+		}
+	}
+	return filepath.Join(publicDirs[0], relPath)
+}
+
+// Whether symlinks are followed "never", only to targets inside the public dir ("same-root"),
+// or "always"
+var followSymlinks = getEnv("FOLLOW_SYMLINKS", "same-root")
+
+// Whether a path in the public dir may be served given the symlinks along it
+func symlinkAllowed(path string) bool {
+	if followSymlinks == "always" {
+		return true
+	}
+	publicDir := publicRoot(path)
+	root, err := filepath.EvalSymlinks(publicDir)
+	if publicDir == "" || err != nil {
+		return false
+	}
+	resolved, err := filepath.EvalSymlinks(path)
+	if err != nil {
+		return false
+	}
+	rel, err := filepath.Rel(root, resolved)
+	if err != nil || rel == ".." || strings.HasPrefix(rel, ".."+string(filepath.Separator)) {
+		return false
+	}
+	if followSymlinks == "never" {
+		// Without symlinks the path resolves to where it sits in the public dir
+		unresolved, err := filepath.Rel(publicDir, path)
+		return err == nil && rel == unresolved
+	}
+	return true
+}
+
+const ignoreFile = ".nanowebignore"
+
+var envExcludes = getGlobs("EXCLUDE", "")
+
+// Files never loaded or served: EXCLUDE plus the public dirs' .nanowebignore, re-read on each walk
+var excludes atomic.Pointer[Globs]
+
+// Read EXCLUDE and the .nanowebignore files (one glob per line, # for comments)
+func loadExcludes() {
+	globs := append(Globs{}, envExcludes...)
+	for _, dir := range publicDirs {
+		dat, err := os.ReadFile(filepath.Join(dir, ignoreFile))
+		if err != nil {
+			continue
+		}
+		var patterns []string
+		for _, line := range strings.Split(string(dat), "\n") {
+			if line = strings.TrimSpace(line); line != "" && !strings.HasPrefix(line, "#") {
+				patterns = append(patterns, line)
+			}
+		}
+		ignored, err := parseGlobs(patterns)
+		if err != nil {
+			fmt.Println("⇨ warning:", err, "in", filepath.Join(dir, ignoreFile))
+		}
+		globs = append(globs, ignored...)
+	}
+	excludes.Store(&globs)
+}
+
+func isExcluded(urlPath string) bool {
+	globs := excludes.Load()
+	return urlPath == "/"+ignoreFile || (globs != nil && globs.Match(urlPath))
+}
+
+// Walk the public dirs and collect the files to create routes for, following symlinks per
+// FOLLOW_SYMLINKS. A file in an earlier dir hides the same path in later ones.
+func collectFiles() []string {
+	loadExcludes()
+	var files []string
+	visited := make(map[string]bool)
+	seen := make(map[string]bool)
+	var walk func(dir string)
+	walk = func(dir string) {
+		// Symlinked directories could otherwise loop
+		if resolved, err := filepath.EvalSymlinks(dir); err == nil {
+			if visited[resolved] {
+				return
+			}
+			visited[resolved] = true
+		}
+		entries, err := os.ReadDir(dir)
+		if err != nil {
+			return
+		}
+		for _, entry := range entries {
+			path := filepath.Join(dir, entry.Name())
+			if isHidden(entry.Name()) {
+				continue
+			}
+			if entry.Type()&fs.ModeSymlink != 0 && !symlinkAllowed(path) {
+				fmt.Println("⇨ warning: skipping symlink", path, "(FOLLOW_SYMLINKS="+followSymlinks+")")
+				continue
+			}
+			info, err := os.Stat(path)
+			if err != nil {
+				continue
+			}
+			if info.IsDir() {
+				walk(path)
+			} else if urlPath := getUrlPath(path); !strings.HasSuffix(path, metaSuffix) && !isExcluded(urlPath) && !seen[urlPath] {
+				seen[urlPath] = true
+				files = append(files, path)
+			}
+		}
+	}
+	for _, dir := range publicDirs {
+		walk(dir)
+	}
+	return files
+}
+
+func getUrlPath(path string) string {
+	relPath, err := filepath.Rel(publicRoot(path), path)
+	if err != nil {
+		return path
+	}
+	return "/" + filepath.ToSlash(relPath)
+}
+
+// With a memory budget the body lives in the cache, and is rebuilt from disk if evicted
+func cacheBody(route *Route) {
+	if bodyCache != nil {
+		bodyCache.Put(route.Source, route.Content)
+		route.Content = Content{}
+	}
+}
+
+var cleanUrls = getEnv("CLEAN_URLS", "0") == "1"
+
+// Names of the documents served for their directory, in order of precedence
+func getIndexFiles() []string {
+	var indexFiles []string
+	for _, name := range strings.Split(getEnv("INDEX_FILES", "index.html"), ",") {
+		if name = strings.TrimSpace(name); name != "" {
+			indexFiles = append(indexFiles, name)
+		}
+	}
+	return indexFiles
+}
+
+var indexFiles = getIndexFiles()
+
+// Position of a file in INDEX_FILES, or -1 if it isn't an index document
+func indexRank(path string) int {
+	base := filepath.Base(path)
+	for i, name := range indexFiles {
+		if base == name {
+			return i
+		}
+	}
+	return -1
+}
+
+// The url paths a file is served under: its own, then for index files its directory's,
+// and with CLEAN_URLS for other HTML files their path without the extension
+func routeKeys(urlPath string, path string) []string {
+	if indexRank(path) == -1 {
+		if cleanUrls && strings.HasSuffix(urlPath, ".html") {
+			return []string{urlPath, strings.TrimSuffix(urlPath, ".html")}
+		}
+		return []string{urlPath}
+	}
+	indexUrlPath := strings.TrimSuffix(strings.TrimSuffix(urlPath, filepath.Base(path)), "/")
+	if indexUrlPath == "" {
+		indexUrlPath = "/"
+	}
+	return []string{urlPath, indexUrlPath, indexUrlPath + "/"}
+}
+
+// Precedence of a file's claim to a url path, lowest first: its own url path, then index
+// aliases in INDEX_FILES order, then clean url aliases
+func aliasRank(key string, path string) int {
+	if getUrlPath(path) == key {
+		return 0
+	}
+	if rank := indexRank(path); rank != -1 {
+		return 1 + rank
+	}
+	return 1 + len(indexFiles)
+}
+
+// Add a route under its url path, and for index files under their directory too,
+// returning the directory's url path for index files
+func addRoute(routes Routes, urlPath string, path string, route *Route) string {
+	keys := routeKeys(urlPath, path)
+	routes[urlPath] = route
+	for _, key := range keys[1:] {
+		if existing, exists := routes[key]; exists && existing.Source != "" && aliasRank(key, existing.Source) <= aliasRank(key, path) {
+			continue
+		}
+		routes[key] = route
+	}
+	if len(keys) == 1 || indexRank(path) == -1 {
+		return ""
+	}
+	return keys[1]
+}
+
+// Whether paths with and without a trailing slash are served as they are ("ignore"), or
+// redirected to the form "always" or "never" having one, or by "redirect" the form matching
+// the route, with a slash for directories and without for files
+var trailingSlash = getEnv("TRAILING_SLASH", "ignore")
+
+func trailingSlashRedirect(urlPath string) (string, bool) {
+	if trailingSlash == "ignore" || urlPath == "/" {
+		return "", false
+	}
+	trimmed := strings.TrimSuffix(urlPath, "/")
+	route, exists := getRoute(trimmed)
+	if !exists {
+		route, exists = getRoute(trimmed + "/")
+	}
+	if !exists {
+		return "", false
+	}
+	canonical := trimmed
+	switch trailingSlash {
+	case "always":
+		canonical = trimmed + "/"
+	case "redirect":
+		if route.JsonIndex != nil || (route.Source != "" && getUrlPath(route.Source) != trimmed && indexRank(route.Source) != -1) {
+			canonical = trimmed + "/"
+		}
+	}
+	if canonical == urlPath {
+		return "", false
+	}
+	if _, exists := getRoute(canonical); !exists {
+		return "", false
+	}
+	return canonical, true
+}
+
+// The canonical url path for an HTML file with CLEAN_URLS, e.g. /about.html → /about
+func cleanUrl(urlPath string) string {
+	if indexRank(urlPath) != -1 {
+		return strings.TrimSuffix(urlPath, filepath.Base(urlPath))
+	}
+	return strings.TrimSuffix(urlPath, ".html")
+}
+
+var strict = getEnv("STRICT", "0") == "1"
+
+// Url paths claimed by more than one file, or overlapping nano-web's own paths, with which wins
+func findConflicts(files []string, urlPaths []string) []string {
+	claims := make(map[string][]string)
+	for i, path := range files {
+		for _, key := range routeKeys(urlPaths[i], path) {
+			claims[key] = append(claims[key], path)
+		}
+	}
+	reserved := reservedPaths()
+	var conflicts []string
+	for key, paths := range claims {
+		if len(paths) > 1 {
+			winner := 0
+			for i, path := range paths {
+				if aliasRank(key, path) < aliasRank(key, paths[winner]) {
+					winner = i
+				}
+			}
+			others := append(append([]string{}, paths[:winner]...), paths[winner+1:]...)
+			conflicts = append(conflicts, key+": "+paths[winner]+" shadows "+strings.Join(others, ", "))
+		}
+		if name, exists := reserved[key]; exists {
+			// Files take precedence over builtin routes, but not over paths handled before lookup
+			if key == envLoaderPath || key == liveReloadScriptPath {
+				conflicts = append(conflicts, key+": "+paths[0]+" shadows "+name)
+			} else {
+				conflicts = append(conflicts, key+": "+name+" shadows "+paths[0])
+			}
+		}
+	}
+	sort.Strings(conflicts)
+	return conflicts
+}
+
+// Warn about route conflicts, refusing to start on any with STRICT=1
+func checkConflicts(conflicts []string) {
+	for _, conflict := range conflicts {
+		fmt.Println("⇨ warning: route conflict", conflict)
+	}
+	if strict && len(conflicts) > 0 {
+		fmt.Println("⇨ refusing to start with", len(conflicts), "route conflicts (STRICT=1)")
+		os.Exit(-1)
+	}
+}
+
+// Routes served by nano-web itself, unless the public dir has a file there
+func addBuiltinRoutes(routes Routes) {
+	if _, exists := routes[envLoaderPath]; !exists {
+		routes[envLoaderPath] = &Route{
+			Content:      Content{Plain: []byte(envLoader)},
+			ContentType:  "text/javascript",
+			LastModified: time.Now().UTC().Format(http.TimeFormat),
+		}
+	}
+	if _, exists := routes[liveReloadScriptPath]; devMode && !exists {
+		routes[liveReloadScriptPath] = &Route{
+			Content:      Content{Plain: []byte(liveReloadScript)},
+			ContentType:  "text/javascript",
+			LastModified: time.Now().UTC().Format(http.TimeFormat),
+		}
+	}
+	for urlPath, route := range routes {
+		if route.Source == "" || getUrlPath(route.Source) != urlPath {
+			continue
+		}
+		for _, alias := range route.RedirectFrom {
+			if _, exists := routes[alias]; !exists {
+				routes[alias] = &Route{Redirect: urlPath}
+			}
+		}
+	}
+	if autoindex {
+		addAutoindexRoutes(routes)
+	}
+	for urlPath, status := range statusOverrides {
+		routes[urlPath] = &Route{Status: status}
+	}
+}
+
+// Url paths served with a fixed status, even where there's a file, e.g. "/old-page=410,/blocked=451"
+func getStatusOverrides() map[string]int {
+	overrides := make(map[string]int)
+	for _, mapping := range strings.Split(getEnv("STATUS_OVERRIDES", ""), ",") {
+		if strings.TrimSpace(mapping) == "" {
+			continue
+		}
+		urlPath, status, found := strings.Cut(strings.TrimSpace(mapping), "=")
+		code, err := strconv.Atoi(status)
+		if !found || err != nil || !strings.HasPrefix(urlPath, "/") || code < 400 || code > 599 {
+			fmt.Println("⇨ invalid mapping in STATUS_OVERRIDES:", mapping)
+			os.Exit(-1)
+		}
+		overrides[urlPath] = code
+	}
+	return overrides
+}
+
+var statusOverrides = getStatusOverrides()
+
+var autoindex = getEnv("AUTOINDEX", "0") == "1"
+var autoindexExclude = getGlobs("AUTOINDEX_EXCLUDE", "")
+
+type IndexEntry struct {
+	Name    string    `json:"name"`
+	Dir     bool      `json:"dir"`
+	Size    int64     `json:"size"`
+	ModTime time.Time `json:"modified"`
+}
+
+type DirectoryIndex struct {
+	Path    string        `json:"path"`
+	Entries []*IndexEntry `json:"entries"`
+}
+
+// Directory contents by directory url path (with a trailing slash), from the routes' file metadata
+func getDirectoryIndexes(routes Routes) map[string]map[string]*IndexEntry {
+	indexes := make(map[string]map[string]*IndexEntry)
+	seen := make(map[*Route]bool)
+	for _, route := range routes {
+		if route.Source == "" || seen[route] {
+			continue
+		}
+		seen[route] = true
+		urlPath := getUrlPath(route.Source)
+		if autoindexExclude.Match(urlPath) || route.scheduledStatus(time.Now()) != 0 || isProtected(urlPath) {
+			continue
+		}
+		modTime := time.Unix(0, route.ModTime).UTC()
+		segments := strings.Split(strings.TrimPrefix(urlPath, "/"), "/")
+		dir := "/"
+		for i, name := range segments {
+			if indexes[dir] == nil {
+				indexes[dir] = make(map[string]*IndexEntry)
+			}
+			entry, exists := indexes[dir][name]
+			if !exists {
+				entry = &IndexEntry{Name: name, Dir: i < len(segments)-1}
+				indexes[dir][name] = entry
+			}
+			if entry.Dir {
+				entry.Size += route.Size
+			} else {
+				entry.Size = route.Size
+			}
+			if modTime.After(entry.ModTime) {
+				entry.ModTime = modTime
+			}
+			dir += name + "/"
+		}
+	}
+	return indexes
+}
+
+// Escape each segment of a url path for use in a link
+func escapeUrlPath(urlPath string) string {
+	segments := strings.Split(urlPath, "/")
+	for i, segment := range segments {
+		segments[i] = url.PathEscape(segment)
+	}
+	return strings.Join(segments, "/")
+}
+
+func renderAutoindex(dir string, entries []*IndexEntry) []byte {
+	var b strings.Builder
+	title := html.EscapeString("Index of " + dir)
+	b.WriteString(`<!DOCTYPE html><html><head><meta charset="utf-8"><title>` + title + `</title></head><body><h1>` + title + "</h1>\n")
+	b.WriteString("<table><tr><th>Name</th><th>Size</th><th>Modified</th></tr>\n")
+	if dir != "/" {
+		parent := dir[:strings.LastIndex(strings.TrimSuffix(dir, "/"), "/")+1]
+		b.WriteString(`<tr><td><a href="` + html.EscapeString(escapeUrlPath(parent)) + `">../</a></td><td></td><td></td></tr>` + "\n")
+	}
+	for _, entry := range entries {
+		name, size := entry.Name, strconv.FormatInt(entry.Size, 10)
+		if entry.Dir {
+			name, size = name+"/", "-"
+		}
+		fmt.Fprintf(&b, "<tr><td><a href=\"%s\">%s</a></td><td>%s</td><td>%s</td></tr>\n",
+			html.EscapeString(escapeUrlPath(dir+name)), html.EscapeString(name), size,
+			entry.ModTime.Format("2006-01-02 15:04:05"))
+	}
+	b.WriteString("</table></body></html>\n")
+	return []byte(b.String())
+}
+
+// Serve a listing for directories without an index.html, directories first then by name
+func addAutoindexRoutes(routes Routes) {
+	for dir, entriesByName := range getDirectoryIndexes(routes) {
+		if _, exists := routes[dir]; exists {
+			continue
+		}
+		entries := make([]*IndexEntry, 0, len(entriesByName))
+		var modTime time.Time
+		for _, entry := range entriesByName {
+			entries = append(entries, entry)
+			if entry.ModTime.After(modTime) {
+				modTime = entry.ModTime
+			}
+		}
+		sort.Slice(entries, func(i, j int) bool {
+			if entries[i].Dir != entries[j].Dir {
+				return entries[i].Dir
+			}
+			return entries[i].Name < entries[j].Name
+		})
+		dat := renderAutoindex(dir, entries)
+		jsonIndex, _ := json.Marshal(DirectoryIndex{Path: dir, Entries: entries})
+		route := &Route{
+			Content:      compressContent(dat, encodings, getStartupLevels()),
+			Hash:         fmt.Sprintf("%x", sha256.Sum256(dat)),
+			ContentType:  "text/html",
+			LastModified: modTime.Format(http.TimeFormat),
+			JsonIndex: &Route{
+				Content:      compressContent(jsonIndex, encodings, getStartupLevels()),
+				Hash:         fmt.Sprintf("%x", sha256.Sum256(jsonIndex)),
+				ContentType:  "application/json",
+				LastModified: modTime.Format(http.TimeFormat),
+			},
+		}
+		routes[dir] = route
+		// A page at the same path, e.g. blog.html with CLEAN_URLS, keeps it
+		if _, exists := routes[strings.TrimSuffix(dir, "/")]; dir != "/" && !exists {
+			routes[strings.TrimSuffix(dir, "/")] = route
+		}
+	}
+}
+
+// What to do when a file can't be made into a route at startup: "skip" it quietly, "warn"
+// about each, or "fail" to start. Skipped files are summarised once routes are populated
+func getOnContentError() string {
+	onContentError := getEnv("ON_CONTENT_ERROR", "warn")
+	switch onContentError {
+	case "skip", "warn", "fail":
+		return onContentError
+	default:
+		fmt.Println("⇨ warning: unknown ON_CONTENT_ERROR", onContentError+", using warn")
+		return "warn"
+	}
+}
+
+var onContentError = getOnContentError()
+
+// Exit codes for startup failures, so orchestration can tell them apart
+const (
+	exitPublicDirMissing = 3
+	exitPublicDirEmpty   = 4
+	exitPortInUse        = 5
+	exitPermissionDenied = 6
+)
+
+// Whether the public dirs may be empty: in dev mode, where content is on its way, and when
+// ORIGIN_URL or GIT_REFS serve content that isn't on disk
+func emptyPublicDirAllowed() bool {
+	return devMode || originUrl != "" || len(gitRefs) > 0
+}
+
+// Fail fast on a missing or unreadable public dir, or when they're all empty and nothing else
+// serves content
+func checkPublicDir() {
+	cwd, _ := os.Getwd()
+	empty := true
+	for _, publicDir := range publicDirs {
+		info, err := os.Stat(publicDir)
+		if errors.Is(err, os.ErrPermission) {
+			fmt.Println("⇨ permission denied reading public directory", publicDir, "in:", cwd)
+			os.Exit(exitPermissionDenied)
+		}
+		if err != nil || !info.IsDir() {
+			fmt.Println("⇨ public directory", publicDir, "not found in:", cwd)
+			os.Exit(exitPublicDirMissing)
+		}
+		err = filepath.WalkDir(publicDir, func(path string, entry fs.DirEntry, err error) error {
+			if err != nil {
+				return err
+			}
+			if !entry.IsDir() {
+				empty = false
+				return fs.SkipAll
+			}
+			return nil
+		})
+		if errors.Is(err, os.ErrPermission) {
+			fmt.Println("⇨ permission denied reading public directory:", err)
+			os.Exit(exitPermissionDenied)
+		}
+	}
+	if empty && !emptyPublicDirAllowed() {
+		fmt.Println("⇨ public directory", strings.Join(publicDirs, ", "), "in", cwd, "is empty")
+		os.Exit(exitPublicDirEmpty)
+	}
+}
+
+// Checks that refuse to start, run before listening so a bad deploy fails before accepting any
+// connections: route conflicts with STRICT=1, and ERROR_PAGES templated against missing assets.
+// Returns the files found, for populateRoutes.
+func preflightRoutes() []string {
+	files := collectFiles()
+	urlPaths := make([]string, len(files))
+	for i, path := range files {
+		urlPaths[i] = getUrlPath(path)
+	}
+	checkConflicts(findConflicts(files, urlPaths))
+	pageAssets := getAssets(urlPaths)
+	for _, code := range sortedKeys(errorPages) {
+		path, exists := resolveFile(errorPages[code])
+		if !exists {
+			continue
+		}
+		var assetErr *missingAssetError
+		if err := checkTemplate(path, pageAssets); errors.As(err, &assetErr) {
+			fmt.Println("⇨ error page for", code, "can't be built:", err)
+			os.Exit(-1)
+		}
+	}
+	return files
+}
+
+// Walk the public dir and create routes for each file
+func populateRoutes(routes Routes, files []string) {
+	urlPaths := make([]string, len(files))
+	for i, path := range files {
+		urlPaths[i] = getUrlPath(path)
+	}
+
+	// Set for cached routes too, so the first reindex only rebuilds templates whose assets changed
+	assetsLock.Lock()
+	assets = getAssets(urlPaths)
+	assetsLock.Unlock()
+
+	if routeCachePath != "" {
+		routeCacheFingerprint = getContentFingerprint(files)
+		if loadRouteCache(routes, routeCachePath, routeCacheFingerprint) {
+			fmt.Println("⇨ loaded routes from cache", routeCachePath)
+			addBuiltinRoutes(routes)
+			return
+		}
+	}
+
+	// Identical files under different paths share one copy of their (compressed) content
+	type sharedContent struct {
+		content Content
+		lazy    *LazyContent
+	}
+	contentsByHash := make(map[string]sharedContent)
+	duplicates := 0
+	var skipped []string
+
+	// Build routes in parallel, then insert them all in one pass below
+	built := make([]*Route, len(files))
+	errs := make([]error, len(files))
+	var wg sync.WaitGroup
+	queue := make(chan int)
+	for w := 0; w < runtime.NumCPU(); w++ {
+		wg.Add(1)
+		go func() {
+			defer wg.Done()
+			for i := range queue {
+				built[i], errs[i] = makeRoute(files[i])
+			}
+		}()
+	}
+	for i := range files {
+		queue <- i
+	}
+	close(queue)
+	wg.Wait()
+
+	for i, path := range files {
+		urlPath := urlPaths[i]
+		route, err := built[i], errs[i]
+
+		if err != nil {
+			if onContentError != "skip" {
+				fmt.Println("⇨ error making route for", urlPath+":", err)
+			}
+			// A template referencing a missing asset is a broken build, so refuse to start
+			var assetErr *missingAssetError
+			if errors.As(err, &assetErr) || onContentError == "fail" {
+				os.Exit(-1)
+			}
+			skipped = append(skipped, urlPath)
+			continue
+		}
+
+		if route.FilePath == "" && bodyCache == nil {
+			hash := contentKey(route)
+			if shared, exists := contentsByHash[hash]; exists {
+				route.Content = shared.content
+				route.Lazy = shared.lazy
+				duplicates++
+			} else {
+				contentsByHash[hash] = sharedContent{route.Content, route.Lazy}
+			}
+		}
+
+		cacheBody(route)
+		if indexUrlPath := addRoute(routes, urlPath, path, route); indexUrlPath != "" {
+			fmt.Println("⇨ adding index", indexUrlPath, "→", path)
+		}
+		fmt.Println("⇨ adding route", urlPath, "→", path)
+	}
+
+	if duplicates > 0 {
+		fmt.Println("⇨ deduplicated", duplicates, "identical files")
+	}
+	if len(skipped) > 0 {
+		fmt.Println("⇨ warning: skipped", len(skipped), "files that couldn't be read:", strings.Join(skipped, ", "))
+	}
+
+	addBuiltinRoutes(routes)
+
+	if routeCachePath != "" {
+		saveRouteCache(routes, routeCachePath, routeCacheFingerprint)
+	}
+}
+
+// What a reindex changed, or would change for a dry run
+type ReloadReport struct {
+	DryRun         bool              `json:"dryRun"`
+	Added          []string          `json:"added"`
+	Changed        []string          `json:"changed"`
+	Removed        []string          `json:"removed"`
+	Conflicts      []string          `json:"conflicts"`
+	TemplateErrors map[string]string `json:"templateErrors"`
+	SizeDelta      int64             `json:"sizeDelta"`
+}
+
+// Serialises reindexes from SIGHUP, the dev watcher and the admin endpoint
+var reindexLock sync.Mutex
+
+// Endpoints nano-web serves itself rather than from the public dir
+func builtinPaths() map[string]string {
+	reserved := map[string]string{envLoaderPath: "the env loader script", readyPath: "the readiness check"}
+	if metricsPath != "" {
+		reserved[metricsPath] = "METRICS_PATH"
+	}
+	if adminToken != "" {
+		reserved[diffPath] = "the admin diff endpoint"
+		reserved[verifyPath] = "the admin verify endpoint"
+		reserved[manifestPath] = "the admin manifest endpoint"
+		reserved[reloadPath] = "the admin reload endpoint"
+	}
+	if oidcIssuer != "" {
+		reserved[oidcCallbackPath] = "the OIDC login callback"
+	}
+	if devMode {
+		reserved[liveReloadPath] = "the live reload event stream"
+		reserved[liveReloadScriptPath] = "the live reload script"
+	}
+	return reserved
+}
+
+// Paths nano-web serves itself, which shadow or are shadowed by public files
+func reservedPaths() map[string]string {
+	reserved := builtinPaths()
+	for urlPath, status := range statusOverrides {
+		reserved[urlPath] = fmt.Sprintf("STATUS_OVERRIDES (%d)", status)
+	}
+	return reserved
+}
+
+// Template a file against the given assets without building its route, to report errors
+func checkTemplate(path string, assets map[string]string) error {
+	ext := strings.ToLower(path[strings.LastIndex(path, "."):])
+	if !templateType(getMimetype(ext)) {
+		return nil
+	}
+	dat, err := os.ReadFile(path)
+	if err != nil {
+		return err
+	}
+	if !shouldTemplate(path, string(dat)) {
+		return nil
+	}
+	_, err = templateRoute(path, string(dat), assets)
+	return err
+}
+
+// Re-scan the public dir, rebuilding only routes whose files were added or changed (by size
+// and mtime) and dropping those whose files were removed, then swap the result in. A dry run
+// only reports what would change, templating changed files to catch errors
+func reindexRoutes(dryRun bool) ReloadReport {
+	reindexLock.Lock()
+	defer reindexLock.Unlock()
+
+	report := ReloadReport{
+		DryRun:         dryRun,
+		Added:          []string{},
+		Changed:        []string{},
+		Removed:        []string{},
+		TemplateErrors: make(map[string]string),
+	}
+	if !publicDirsAvailable() {
+		degraded.Store(true)
+		fmt.Println("⇨ warning: public directory unavailable, not reindexing")
+		return report
+	}
+	if !dryRun {
+		gitBlobs.Clear()
+	}
+	files := collectFiles()
+	urlPaths := make([]string, len(files))
+	for i, path := range files {
+		urlPaths[i] = getUrlPath(path)
+	}
+	report.Conflicts = findConflicts(files, urlPaths)
+	if report.Conflicts == nil {
+		report.Conflicts = []string{}
+	}
+	if strict && len(report.Conflicts) > 0 && !dryRun {
+		fmt.Println("⇨ not reindexing with", len(report.Conflicts), "route conflicts (STRICT=1)")
+		return report
+	}
+	newAssets := getAssets(urlPaths)
+	assetsLock.Lock()
+	// Templated files may reference assets whose fingerprinted names changed
+	assetsChanged := fmt.Sprint(assets) != fmt.Sprint(newAssets)
+	if !dryRun {
+		assets = newAssets
+	}
+	assetsLock.Unlock()
+
+	existing := make(map[string]*Route)
+	routesLock.RLock()
+	for _, route := range routes {
+		if route.Source != "" {
+			existing[route.Source] = route
+		}
+	}
+	routesLock.RUnlock()
+
+	next := make(Routes)
+	seen := make(map[string]bool)
+	for i, path := range files {
+		info, err := os.Stat(path)
+		if err != nil {
+			continue
+		}
+		seen[path] = true
+		route, exists := existing[path]
+		if exists {
+			report.SizeDelta -= route.Size
+		}
+		report.SizeDelta += info.Size()
+		stale := !exists || route.Size != info.Size() || route.ModTime != info.ModTime().UnixNano() ||
+			route.MetaModTime != metaModTime(path) || (assetsChanged && templateType(route.ContentType))
+		if stale && dryRun {
+			if err := checkTemplate(path, newAssets); err != nil {
+				report.TemplateErrors[urlPaths[i]] = err.Error()
+			}
+			route = &Route{Source: path}
+		} else if stale {
+			rebuilt, err := makeRoute(path)
+			if err != nil {
+				fmt.Println("⇨ error making route for", urlPaths[i]+":", err)
+				report.TemplateErrors[urlPaths[i]] = err.Error()
+				continue
+			}
+			fmt.Println("⇨ updating route", urlPaths[i], "→", path)
+			route = rebuilt
+			cacheBody(route)
+		}
+		if stale && exists {
+			report.Changed = append(report.Changed, urlPaths[i])
+		} else if stale {
+			report.Added = append(report.Added, urlPaths[i])
+		}
+		addRoute(next, urlPaths[i], path, route)
+	}
+	addBuiltinRoutes(next)
+
+	for source, route := range existing {
+		if !seen[source] {
+			report.Removed = append(report.Removed, getUrlPath(source))
+			report.SizeDelta -= route.Size
+		}
+	}
+	sort.Strings(report.Removed)
+	if dryRun {
+		return report
+	}
+
+	routesLock.Lock()
+	routes = next
+	routesLock.Unlock()
+	fmt.Println("⇨ reindexed routes:", len(report.Added), "added,", len(report.Changed), "changed,", len(report.Removed), "removed")
+	if len(report.Added)+len(report.Changed)+len(report.Removed) > 0 {
+		liveReload.notify()
+	}
+	recordGeneration()
+	return report
+}
+
+type VerifyReport struct {
+	Checked int      `json:"checked"`
+	Drifted []string `json:"drifted"`
+	Missing []string `json:"missing"`
+}
+
+// Re-hash a random sample of files (all of them with a sample of 0) and compare them to the
+// content they were served from, catching files edited in place without a reload. Files
+// streamed from disk aren't held in memory, so can't drift.
+func verifyRoutes(sample int) VerifyReport {
+	report := VerifyReport{Drifted: []string{}, Missing: []string{}}
+	var sources []*Route
+	seen := make(map[*Route]bool)
+	routesLock.RLock()
+	for _, route := range routes {
+		if route.SourceHash != "" && !seen[route] {
+			seen[route] = true
+			sources = append(sources, route)
+		}
+	}
+	routesLock.RUnlock()
+	rand.Shuffle(len(sources), func(i, j int) { sources[i], sources[j] = sources[j], sources[i] })
+	if sample > 0 && len(sources) > sample {
+		sources = sources[:sample]
+	}
+	for _, route := range sources {
+		report.Checked++
+		dat, err := os.ReadFile(route.Source)
+		if err != nil {
+			report.Missing = append(report.Missing, getUrlPath(route.Source))
+		} else if fmt.Sprintf("%x", sha256.Sum256(dat)) != route.SourceHash {
+			report.Drifted = append(report.Drifted, getUrlPath(route.Source))
+		}
+	}
+	sort.Strings(report.Drifted)
+	sort.Strings(report.Missing)
+	return report
+}
+
+// Verify a sample of files every VERIFY_INTERVAL, logging a warning event for any that drifted
+func monitorDrift(interval time.Duration, sample int) {
+	for range time.Tick(interval) {
+		if degraded.Load() {
+			continue
+		}
+		report := verifyRoutes(sample)
+		for _, urlPath := range report.Drifted {
+			fmt.Println("⇨ warning: content_drift kind=changed path=" + urlPath)
+		}
+		for _, urlPath := range report.Missing {
+			fmt.Println("⇨ warning: content_drift kind=missing path=" + urlPath)
+		}
+	}
+}
+
+// Verify every file, or a sample with ?sample=N, and respond with the report
+func handleVerify(ctx *fasthttp.RequestCtx) {
+	sample, _ := strconv.Atoi(string(ctx.QueryArgs().Peek("sample")))
+	dat, err := json.Marshal(verifyRoutes(sample))
+	if err != nil {
+		ctx.Error("Internal Server Error", fasthttp.StatusInternalServerError)
+		return
+	}
+	ctx.SetContentType("application/json")
+	ctx.SetBody(dat)
+}
+
+// Set while the public dirs can't be read, e.g. an NFS or bind mount gone away, during which the
+// in-memory routes keep being served as they were
+var degraded atomic.Bool
+
+// Whether every public dir can be read. An empty dir counts as unavailable too when startup refuses
+// one, since an unmounted volume usually leaves its empty mountpoint behind.
+func publicDirsAvailable() bool {
+	for _, dir := range publicDirs {
+		file, err := os.Open(dir)
+		if err != nil {
+			return false
+		}
+		_, err = file.Readdirnames(1)
+		file.Close()
+		if err != nil && (err != io.EOF || !emptyPublicDirAllowed()) {
+			return false
+		}
+	}
+	return true
+}
+
+// Poll the public dirs, degrading while they're unavailable and reindexing once they're back
+func monitorPublicDirs(interval time.Duration) {
+	for range time.Tick(interval) {
+		available := publicDirsAvailable()
+		if !available && !degraded.Swap(true) {
+			fmt.Println("⇨ warning: public directory unavailable, serving the in-memory routes until it's back")
+		} else if available && degraded.Swap(false) {
+			if productionMode {
+				fmt.Println("⇨ public directory available again, send SIGHUP to reindex")
+				continue
+			}
+			fmt.Println("⇨ public directory available again, reindexing")
+			reindexRoutes(false)
+		}
+	}
+}
+
+// Reindex on SIGHUP, so deploys can update content without a restart
+func handleReload() {
+	signals := make(chan os.Signal, 1)
+	signal.Notify(signals, syscall.SIGHUP)
+	for range signals {
+		fmt.Println("⇨ reloading")
+		reindexRoutes(false)
+	}
+}
+
+// Content hashes by url path of the current and previous generation of routes
+type Generations struct {
+	lock     sync.Mutex
+	id       int
+	current  map[string]string
+	previous map[string]string
+}
+
+var generations Generations
+
+// Snapshot the routes as a new generation, after population and each reindex
+func recordGeneration() {
+	snapshot := make(map[string]string)
+	routesLock.RLock()
+	for urlPath, route := range routes {
+		snapshot[urlPath] = route.Hash
+	}
+	routesLock.RUnlock()
+	generations.lock.Lock()
+	defer generations.lock.Unlock()
+	generations.id++
+	generations.previous = generations.current
+	generations.current = snapshot
+}
+
+type changedRoute struct {
+	Path string `json:"path"`
+	From string `json:"from"`
+	To   string `json:"to"`
+}
+
+type generationDiff struct {
+	From    int            `json:"from"`
+	To      int            `json:"to"`
+	Added   []changedRoute `json:"added"`
+	Removed []changedRoute `json:"removed"`
+	Changed []changedRoute `json:"changed"`
+}
+
+func diffGenerations() generationDiff {
+	generations.lock.Lock()
+	defer generations.lock.Unlock()
+	diff := generationDiff{
+		From:    generations.id - 1,
+		To:      generations.id,
+		Added:   []changedRoute{},
+		Removed: []changedRoute{},
+		Changed: []changedRoute{},
+	}
+	for urlPath, hash := range generations.current {
+		previous, exists := generations.previous[urlPath]
+		if !exists {
+			diff.Added = append(diff.Added, changedRoute{Path: urlPath, To: hash})
+		} else if previous != hash {
+			diff.Changed = append(diff.Changed, changedRoute{Path: urlPath, From: previous, To: hash})
+		}
+	}
+	for urlPath, hash := range generations.previous {
+		if _, exists := generations.current[urlPath]; !exists {
+			diff.Removed = append(diff.Removed, changedRoute{Path: urlPath, From: hash})
+		}
+	}
+	for _, changes := range [][]changedRoute{diff.Added, diff.Removed, diff.Changed} {
+		sort.Slice(changes, func(i, j int) bool { return changes[i].Path < changes[j].Path })
+	}
+	return diff
+}
+
+type AuthResult int
+
+const (
+	AuthAllow AuthResult = iota
+	AuthDeny
+	AuthChallenge
+	AuthRedirect
+)
+
+// Decides whether a request may be served: allowed, denied with a 403, challenged with a 401 and
+// the WWW-Authenticate header returned alongside, or redirected to the url returned alongside to
+// log in. Providers are only asked about requests for content under paths they protect.
+type AuthProvider interface {
+	Protects(urlPath string) bool
+	Validate(ctx *fasthttp.RequestCtx) (AuthResult, string)
+}
+
+func underPrefix(urlPath string, prefix string) bool {
+	return urlPath == prefix || strings.HasPrefix(urlPath, strings.TrimSuffix(prefix, "/")+"/")
+}
+
+// Requires a user from a list for paths under a prefix
+type BasicAuth struct {
+	Prefix string
+	Users  map[string]string
+}
+
+// Whether a password matches a bcrypt hash
+func checkPassword(hash string, password string) bool {
+	return bcrypt.CompareHashAndPassword([]byte(hash), []byte(password)) == nil
+}
+
+func (auth *BasicAuth) Protects(urlPath string) bool {
+	return underPrefix(urlPath, auth.Prefix)
+}
+
+func (auth *BasicAuth) Validate(ctx *fasthttp.RequestCtx) (AuthResult, string) {
+	credentials, found := strings.CutPrefix(string(ctx.Request.Header.Peek("Authorization")), "Basic ")
+	if decoded, err := base64.StdEncoding.DecodeString(credentials); found && err == nil {
+		user, password, _ := strings.Cut(string(decoded), ":")
+		if hash, exists := auth.Users[user]; exists && checkPassword(hash, password) {
+			return AuthAllow, ""
+		}
+	}
+	return AuthChallenge, `Basic realm="` + auth.Prefix + `", charset="UTF-8"`
+}
+
+// Comma separated BASIC_AUTH prefixes and the users allowed them with bcrypt hashes of their
+// passwords, e.g. "/private=alice:$2y$10$...,/private=bob:$2y$10$..."
+func getBasicAuth() []AuthProvider {
+	byPrefix := make(map[string]*BasicAuth)
+	for _, mapping := range strings.Split(getEnv("BASIC_AUTH", ""), ",") {
+		if strings.TrimSpace(mapping) == "" {
+			continue
+		}
+		prefix, credentials, _ := strings.Cut(strings.TrimSpace(mapping), "=")
+		user, hash, _ := strings.Cut(credentials, ":")
+		if !strings.HasPrefix(prefix, "/") || user == "" {
+			fmt.Println("⇨ invalid mapping in BASIC_AUTH:", mapping)
+			os.Exit(-1)
+		}
+		if _, err := bcrypt.Cost([]byte(hash)); err != nil {
+			fmt.Println("⇨ invalid hash for", user, "in BASIC_AUTH, expected bcrypt:", err)
+			os.Exit(-1)
+		}
+		if byPrefix[prefix] == nil {
+			byPrefix[prefix] = &BasicAuth{Prefix: prefix, Users: make(map[string]string)}
+		}
+		byPrefix[prefix].Users[user] = hash
+	}
+	var providers []AuthProvider
+	for _, prefix := range sortedKeys(byPrefix) {
+		providers = append(providers, byPrefix[prefix])
+	}
+	return providers
+}
+
+const oidcCallbackPath = "/_oidc/callback"
+const oidcSessionCookie = "nano_web_session"
+const oidcStateCookie = "nano_web_oidc"
+
+// An OpenID Connect issuer users log in with before paths under OIDC_PREFIX are served
+var oidcIssuer = strings.TrimSuffix(getEnv("OIDC_ISSUER", ""), "/")
+var oidcClientId = getEnv("OIDC_CLIENT_ID", "")
+var oidcClientSecret = getEnv("OIDC_CLIENT_SECRET", "")
+var oidcRedirectUrl = getEnv("OIDC_REDIRECT_URL", "")
+var oidcSessionTtl = time.Duration(getEnvInt("OIDC_SESSION_TTL", 43200)) * time.Second
+
+var oidcClient = &http.Client{Timeout: 10 * time.Second}
+
+// The key session cookies are signed with, from OIDC_SESSION_SECRET, or random per process so
+// sessions end on restart
+func getSessionKey() []byte {
+	if secret := getEnv("OIDC_SESSION_SECRET", ""); secret != "" {
+		return []byte(secret)
+	}
+	key := make([]byte, 32)
+	cryptorand.Read(key)
+	return key
+}
+
+var sessionKey = getSessionKey()
+
+func randomToken() string {
+	token := make([]byte, 16)
+	cryptorand.Read(token)
+	return hex.EncodeToString(token)
+}
+
+// Encode a value as JSON with an HMAC, so it can be trusted when it comes back in a cookie
+func signValue(value any) string {
+	dat, _ := json.Marshal(value)
+	payload := base64.RawURLEncoding.EncodeToString(dat)
+	mac := hmac.New(sha256.New, sessionKey)
+	mac.Write([]byte(payload))
+	return payload + "." + hex.EncodeToString(mac.Sum(nil))
+}
+
+func verifyValue(signed string, value any) bool {
+	payload, signature, found := strings.Cut(signed, ".")
+	mac := hmac.New(sha256.New, sessionKey)
+	mac.Write([]byte(payload))
+	if !found || !hmac.Equal([]byte(signature), []byte(hex.EncodeToString(mac.Sum(nil)))) {
+		return false
+	}
+	dat, err := base64.RawURLEncoding.DecodeString(payload)
+	return err == nil && json.Unmarshal(dat, value) == nil
+}
+
+type OidcSession struct {
+	Subject string `json:"sub"`
+	Expires int64  `json:"exp"`
+}
+
+// A login in progress, kept in a cookie until the IdP redirects back
+type OidcState struct {
+	State    string `json:"state"`
+	Nonce    string `json:"nonce"`
+	ReturnTo string `json:"return_to"`
+	Expires  int64  `json:"exp"`
+}
+
+type OidcProvider struct {
+	Issuer                string `json:"issuer"`
+	AuthorizationEndpoint string `json:"authorization_endpoint"`
+	TokenEndpoint         string `json:"token_endpoint"`
+	JwksUri               string `json:"jwks_uri"`
+}
+
+var oidcProvider atomic.Pointer[OidcProvider]
+
+// The issuer's endpoints from its discovery document, fetched when first needed
+func getOidcProvider() (*OidcProvider, error) {
+	if provider := oidcProvider.Load(); provider != nil {
+		return provider, nil
+	}
+	response, err := oidcClient.Get(oidcIssuer + "/.well-known/openid-configuration")
+	if err != nil {
+		return nil, err
+	}
+	defer response.Body.Close()
+	if response.StatusCode != http.StatusOK {
+		return nil, fmt.Errorf("discovery responded %s", response.Status)
+	}
+	var provider OidcProvider
+	if err := json.NewDecoder(response.Body).Decode(&provider); err != nil {
+		return nil, err
+	}
+	if strings.TrimSuffix(provider.Issuer, "/") != oidcIssuer {
+		return nil, fmt.Errorf("discovery issuer %s doesn't match OIDC_ISSUER", provider.Issuer)
+	}
+	for _, endpoint := range []string{provider.AuthorizationEndpoint, provider.TokenEndpoint, provider.JwksUri} {
+		if !strings.HasPrefix(endpoint, "https://") {
+			return nil, fmt.Errorf("discovery endpoint %q isn't https", endpoint)
+		}
+	}
+	oidcProvider.Store(&provider)
+	return &provider, nil
+}
+
+// A key from the issuer's JWKS
+type Jwk struct {
+	Kid string `json:"kid"`
+	Kty string `json:"kty"`
+	Use string `json:"use"`
+	N   string `json:"n"`
+	E   string `json:"e"`
+	Crv string `json:"crv"`
+	X   string `json:"x"`
+	Y   string `json:"y"`
+}
+
+func decodeBigInt(value string) (*big.Int, error) {
+	dat, err := base64.RawURLEncoding.DecodeString(value)
+	if err != nil || len(dat) == 0 {
+		return nil, errors.New("malformed key parameter")
+	}
+	return new(big.Int).SetBytes(dat), nil
+}
+
+func (jwk Jwk) publicKey() (crypto.PublicKey, error) {
+	switch jwk.Kty {
+	case "RSA":
+		n, err := decodeBigInt(jwk.N)
+		if err != nil {
+			return nil, err
+		}
+		e, err := decodeBigInt(jwk.E)
+		if err != nil || !e.IsInt64() {
+			return nil, errors.New("malformed key exponent")
+		}
+		return &rsa.PublicKey{N: n, E: int(e.Int64())}, nil
+	case "EC":
+		curves := map[string]elliptic.Curve{"P-256": elliptic.P256(), "P-384": elliptic.P384(), "P-521": elliptic.P521()}
+		curve, exists := curves[jwk.Crv]
+		if !exists {
+			return nil, fmt.Errorf("unsupported curve %s", jwk.Crv)
+		}
+		x, err := decodeBigInt(jwk.X)
+		if err != nil {
+			return nil, err
+		}
+		y, err := decodeBigInt(jwk.Y)
+		if err != nil {
+			return nil, err
+		}
+		return &ecdsa.PublicKey{Curve: curve, X: x, Y: y}, nil
+	}
+	return nil, fmt.Errorf("unsupported key type %s", jwk.Kty)
+}
+
+func fetchJwks() (map[string]crypto.PublicKey, error) {
+	provider, err := getOidcProvider()
+	if err != nil {
+		return nil, err
+	}
+	response, err := oidcClient.Get(provider.JwksUri)
+	if err != nil {
+		return nil, err
+	}
+	defer response.Body.Close()
+	if response.StatusCode != http.StatusOK {
+		return nil, fmt.Errorf("jwks responded %s", response.Status)
+	}
+	var set struct {
+		Keys []Jwk `json:"keys"`
+	}
+	if err := json.NewDecoder(response.Body).Decode(&set); err != nil {
+		return nil, err
+	}
+	keys := make(map[string]crypto.PublicKey)
+	for _, jwk := range set.Keys {
+		if jwk.Use == "enc" {
+			continue
+		}
+		key, err := jwk.publicKey()
+		if err != nil {
+			fmt.Println("⇨ warning: skipping OIDC signing key", jwk.Kid+":", err)
+			continue
+		}
+		keys[jwk.Kid] = key
+	}
+	return keys, nil
+}
+
+// The issuer's signing keys by key id, refetched at most once a minute when a token names a key
+// that isn't known, e.g. after the IdP rotates them
+type OidcKeys struct {
+	lock    sync.Mutex
+	keys    map[string]crypto.PublicKey
+	fetched time.Time
+}
+
+var oidcKeys OidcKeys
+
+func (keys *OidcKeys) Get(kid string) (crypto.PublicKey, error) {
+	keys.lock.Lock()
+	defer keys.lock.Unlock()
+	if key, exists := keys.keys[kid]; exists {
+		return key, nil
+	}
+	if time.Since(keys.fetched) > time.Minute {
+		fetched, err := fetchJwks()
+		if err != nil {
+			return nil, err
+		}
+		keys.keys, keys.fetched = fetched, time.Now()
+	}
+	if key, exists := keys.keys[kid]; exists {
+		return key, nil
+	}
+	return nil, fmt.Errorf("unknown signing key %q", kid)
+}
+
+var jwtHashes = map[string]crypto.Hash{"256": crypto.SHA256, "384": crypto.SHA384, "512": crypto.SHA512}
+
+// Check a JWT's signature with the issuer's keys. Only RSA and ECDSA algorithms are accepted, so a
+// token can't choose "none" or an HMAC keyed with a public key.
+func verifyJwtSignature(parts []string) error {
+	dat, err := base64.RawURLEncoding.DecodeString(parts[0])
+	if err != nil {
+		return err
+	}
+	var header struct {
+		Alg string `json:"alg"`
+		Kid string `json:"kid"`
+	}
+	if err := json.Unmarshal(dat, &header); err != nil {
+		return err
+	}
+	signature, err := base64.RawURLEncoding.DecodeString(parts[2])
+	if err != nil {
+		return err
+	}
+	if len(header.Alg) != 5 {
+		return fmt.Errorf("unsupported id token algorithm %q", header.Alg)
+	}
+	hash, exists := jwtHashes[header.Alg[2:]]
+	if !exists {
+		return fmt.Errorf("unsupported id token algorithm %q", header.Alg)
+	}
+	key, err := oidcKeys.Get(header.Kid)
+	if err != nil {
+		return err
+	}
+	hasher := hash.New()
+	hasher.Write([]byte(parts[0] + "." + parts[1]))
+	digest := hasher.Sum(nil)
+	switch key := key.(type) {
+	case *rsa.PublicKey:
+		switch header.Alg[:2] {
+		case "RS":
+			return rsa.VerifyPKCS1v15(key, hash, digest, signature)
+		case "PS":
+			return rsa.VerifyPSS(key, hash, digest, signature, nil)
+		}
+	case *ecdsa.PublicKey:
+		size := (key.Curve.Params().BitSize + 7) / 8
+		if header.Alg[:2] == "ES" && len(signature) == 2*size {
+			r := new(big.Int).SetBytes(signature[:size])
+			s := new(big.Int).SetBytes(signature[size:])
+			if ecdsa.Verify(key, digest, r, s) {
+				return nil
+			}
+			return errors.New("invalid id token signature")
+		}
+	}
+	return fmt.Errorf("id token algorithm %s doesn't match its key", header.Alg)
+}
+
+// Where the IdP sends users back to, by default the callback on the requested host over https
+func oidcRedirectTarget(ctx *fasthttp.RequestCtx) string {
+	if oidcRedirectUrl != "" {
+		return oidcRedirectUrl
+	}
+	return "https://" + string(ctx.Host()) + oidcCallbackPath
+}
+
+// Whether a request is a browser navigating to a page, which can follow a login redirect, rather
+// than a fetch or subresource that would start a login of its own
+func isNavigation(ctx *fasthttp.RequestCtx) bool {
+	if mode := ctx.Request.Header.Peek("Sec-Fetch-Mode"); len(mode) > 0 {
+		return string(mode) == "navigate"
+	}
+	return ctx.IsGet() && strings.Contains(string(ctx.Request.Header.Peek("Accept")), "text/html")
+}
+
+func setCookie(ctx *fasthttp.RequestCtx, name string, value string, maxAge time.Duration) {
+	cookie := fasthttp.AcquireCookie()
+	defer fasthttp.ReleaseCookie(cookie)
+	cookie.SetKey(name)
+	cookie.SetValue(value)
+	cookie.SetPath("/")
+	cookie.SetMaxAge(int(maxAge / time.Second))
+	cookie.SetHTTPOnly(true)
+	cookie.SetSecure(strings.HasPrefix(oidcRedirectTarget(ctx), "https://"))
+	cookie.SetSameSite(fasthttp.CookieSameSiteLaxMode)
+	ctx.Response.Header.SetCookie(cookie)
+}
+
+// Expire a cookie set by setCookie, matching its path so the browser drops it
+func deleteCookie(ctx *fasthttp.RequestCtx, name string) {
+	cookie := fasthttp.AcquireCookie()
+	defer fasthttp.ReleaseCookie(cookie)
+	cookie.SetKey(name)
+	cookie.SetPath("/")
+	cookie.SetExpire(fasthttp.CookieExpireDelete)
+	cookie.SetHTTPOnly(true)
+	ctx.Response.Header.SetCookie(cookie)
+}
+
+// Requires a session from logging in with the OIDC issuer for paths under a prefix
+type OidcAuth struct {
+	Prefix string
+}
+
+// Built in endpoints are left to their own auth, as scrapers and the CLI can't log in with a browser
+var oidcExempt = builtinPaths()
+
+func (auth OidcAuth) Protects(urlPath string) bool {
+	_, exempt := oidcExempt[urlPath]
+	return !exempt && underPrefix(urlPath, auth.Prefix)
+}
+
+func (auth OidcAuth) Validate(ctx *fasthttp.RequestCtx) (AuthResult, string) {
+	var session OidcSession
+	if verifyValue(string(ctx.Request.Header.Cookie(oidcSessionCookie)), &session) && time.Now().Unix() < session.Expires {
+		return AuthAllow, ""
+	}
+	// Only navigations start a login, so a page's parallel asset requests don't each replace it
+	if !isNavigation(ctx) {
+		return AuthChallenge, ""
+	}
+	provider, err := getOidcProvider()
+	if err != nil {
+		fmt.Println("⇨ error discovering OIDC issuer:", err)
+		return AuthDeny, ""
+	}
+	state := OidcState{
+		State:    randomToken(),
+		Nonce:    randomToken(),
+		ReturnTo: string(ctx.RequestURI()),
+		Expires:  time.Now().Add(10 * time.Minute).Unix(),
+	}
+	// Keyed by state, so logins started in several tabs don't overwrite each other
+	setCookie(ctx, oidcStateCookie+"_"+state.State, signValue(state), 10*time.Minute)
+	query := url.Values{
+		"response_type": {"code"},
+		"client_id":     {oidcClientId},
+		"redirect_uri":  {oidcRedirectTarget(ctx)},
+		"scope":         {"openid"},
+		"state":         {state.State},
+		"nonce":         {state.Nonce},
+	}
+	separator := "?"
+	if strings.Contains(provider.AuthorizationEndpoint, "?") {
+		separator = "&"
+	}
+	return AuthRedirect, provider.AuthorizationEndpoint + separator + query.Encode()
+}
+
+type IdTokenClaims struct {
+	Issuer   string          `json:"iss"`
+	Subject  string          `json:"sub"`
+	Audience json.RawMessage `json:"aud"`
+	Expires  int64           `json:"exp"`
+	Nonce    string          `json:"nonce"`
+}
+
+// Check an ID token's signature with the issuer's keys, then that its claims are for this client
+// and login
+func verifyIdToken(idToken string, nonce string) (*IdTokenClaims, error) {
+	parts := strings.Split(idToken, ".")
+	if len(parts) != 3 {
+		return nil, errors.New("malformed id token")
+	}
+	if err := verifyJwtSignature(parts); err != nil {
+		return nil, err
+	}
+	dat, err := base64.RawURLEncoding.DecodeString(parts[1])
+	if err != nil {
+		return nil, err
+	}
+	var claims IdTokenClaims
+	if err := json.Unmarshal(dat, &claims); err != nil {
+		return nil, err
+	}
+	var audiences []string
+	if json.Unmarshal(claims.Audience, &audiences) != nil {
+		var audience string
+		json.Unmarshal(claims.Audience, &audience)
+		audiences = []string{audience}
+	}
+	switch {
+	case strings.TrimSuffix(claims.Issuer, "/") != oidcIssuer:
+		return nil, fmt.Errorf("id token issuer %s doesn't match", claims.Issuer)
+	case !slices.Contains(audiences, oidcClientId):
+		return nil, errors.New("id token isn't for this client")
+	case time.Now().Unix() >= claims.Expires:
+		return nil, errors.New("id token expired")
+	case claims.Nonce != nonce:
+		return nil, errors.New("id token nonce doesn't match")
+	}
+	return &claims, nil
+}
+
+// Exchange the code the IdP redirected back with for an ID token, start a session and return
+// to the page the login started from
+func handleOidcCallback(ctx *fasthttp.RequestCtx) {
+	var state OidcState
+	stateCookie := oidcStateCookie + "_" + string(ctx.QueryArgs().Peek("state"))
+	if !verifyValue(string(ctx.Request.Header.Cookie(stateCookie)), &state) || time.Now().Unix() >= state.Expires ||
+		string(ctx.QueryArgs().Peek("state")) != state.State {
+		serveError(ctx, fasthttp.StatusBadRequest)
+		return
+	}
+	deleteCookie(ctx, stateCookie)
+	if len(ctx.QueryArgs().Peek("error")) > 0 {
+		fmt.Println("⇨ OIDC login failed:", string(ctx.QueryArgs().Peek("error")))
+		serveError(ctx, fasthttp.StatusForbidden)
+		return
+	}
+	claims, err := exchangeOidcCode(ctx, string(ctx.QueryArgs().Peek("code")), state.Nonce)
+	if err != nil {
+		fmt.Println("⇨ error completing OIDC login:", err)
+		serveError(ctx, fasthttp.StatusBadGateway)
+		return
+	}
+	session := OidcSession{Subject: claims.Subject, Expires: time.Now().Add(oidcSessionTtl).Unix()}
+	setCookie(ctx, oidcSessionCookie, signValue(session), oidcSessionTtl)
+	returnTo := state.ReturnTo
+	if !strings.HasPrefix(returnTo, "/") || strings.HasPrefix(returnTo, "//") {
+		returnTo = "/"
+	}
+	ctx.Redirect(returnTo, fasthttp.StatusFound)
+}
+
+func exchangeOidcCode(ctx *fasthttp.RequestCtx, code string, nonce string) (*IdTokenClaims, error) {
+	provider, err := getOidcProvider()
+	if err != nil {
+		return nil, err
+	}
+	response, err := oidcClient.PostForm(provider.TokenEndpoint, url.Values{
+		"grant_type":    {"authorization_code"},
+		"code":          {code},
+		"redirect_uri":  {oidcRedirectTarget(ctx)},
+		"client_id":     {oidcClientId},
+		"client_secret": {oidcClientSecret},
+	})
+	if err != nil {
+		return nil, err
+	}
+	defer response.Body.Close()
+	if response.StatusCode != http.StatusOK {
+		return nil, fmt.Errorf("token endpoint responded %s", response.Status)
+	}
+	var tokens struct {
+		IdToken string `json:"id_token"`
+	}
+	if err := json.NewDecoder(response.Body).Decode(&tokens); err != nil {
+		return nil, err
+	}
+	return verifyIdToken(tokens.IdToken, nonce)
+}
+
+func getAuthProviders() []AuthProvider {
+	providers := getBasicAuth()
+	if oidcIssuer != "" {
+		if oidcClientId == "" {
+			fmt.Println("⇨ OIDC_ISSUER needs OIDC_CLIENT_ID")
+			os.Exit(-1)
+		}
+		if !strings.HasPrefix(oidcIssuer, "https://") {
+			fmt.Println("⇨ invalid OIDC_ISSUER, expected an https url:", oidcIssuer)
+			os.Exit(-1)
+		}
+		providers = append(providers, OidcAuth{getEnv("OIDC_PREFIX", "/")})
+	}
+	return providers
+}
+
+// Checked in order before route lookup, the first to not allow a request deciding its response
+var authProviders = getAuthProviders()
+
+// Whether any auth provider protects a url path, so it's left out of listings
+func isProtected(urlPath string) bool {
+	return slices.ContainsFunc(authProviders, func(provider AuthProvider) bool {
+		return provider.Protects(urlPath)
+	})
+}
+
+// The paths a request's content may come from, so protected files can't be reached under another
+// url: the request path, the file its route was built from, the path within a /_v/<ref>/ version
+// and the archive an entry is read from
+func contentPaths(urlPath string) []string {
+	paths := []string{urlPath}
+	if rest, found := strings.CutPrefix(urlPath, gitVersionPrefix); found && len(gitRefs) > 0 {
+		_, versioned, _ := strings.Cut(rest, "/")
+		versioned = path.Clean("/" + versioned)
+		paths = append(paths, versioned)
+		for _, indexFile := range indexFiles {
+			paths = append(paths, path.Join(versioned, indexFile))
+		}
+	}
+	if route, exists := getRoute(urlPath); exists && route.Source != "" {
+		paths = append(paths, getUrlPath(route.Source))
+	}
+	if archive, _, exists := findArchive(urlPath); exists {
+		paths = append(paths, getUrlPath(archive.Source))
+	}
+	return paths
+}
+
+// Run the auth providers ahead of a handler. WWW-Authenticate is set after the error response,
+// which resets headers.
+func withAuth(next fasthttp.RequestHandler) fasthttp.RequestHandler {
+	return func(ctx *fasthttp.RequestCtx) {
+		paths := contentPaths(string(ctx.Path()))
+		for _, provider := range authProviders {
+			if !slices.ContainsFunc(paths, provider.Protects) {
+				continue
+			}
+			switch result, challenge := provider.Validate(ctx); result {
+			case AuthDeny:
+				serveError(ctx, fasthttp.StatusForbidden)
+				return
+			case AuthChallenge:
+				serveError(ctx, fasthttp.StatusUnauthorized)
+				if challenge != "" {
+					ctx.Response.Header.Set("WWW-Authenticate", challenge)
+				}
+				return
+			case AuthRedirect:
+				ctx.Redirect(challenge, fasthttp.StatusFound)
+				return
+			}
+		}
+		next(ctx)
+	}
+}
+
+// Allows requests bearing a token, e.g. for the admin endpoints
+type BearerTokenAuth struct {
+	Token string
+}
+
+// Guards whatever it's asked about, since it's checked directly rather than by prefix
+func (auth BearerTokenAuth) Protects(urlPath string) bool {
+	return true
+}
+
+func (auth BearerTokenAuth) Validate(ctx *fasthttp.RequestCtx) (AuthResult, string) {
+	expected := []byte("Bearer " + auth.Token)
+	if auth.Token != "" && subtle.ConstantTimeCompare(ctx.Request.Header.Peek("Authorization"), expected) == 1 {
+		return AuthAllow, ""
+	}
+	return AuthChallenge, "Bearer"
+}
+
+var adminToken = getEnv("ADMIN_TOKEN", "")
+var adminAuth = BearerTokenAuth{adminToken}
+
+// Admin endpoints are only served when ADMIN_TOKEN is set, to requests bearing it
+func isAdmin(ctx *fasthttp.RequestCtx) bool {
+	result, _ := adminAuth.Validate(ctx)
+	return result == AuthAllow
+}
+
+const diffPath = "/_api/diff"
+const verifyPath = "/_api/verify"
+const manifestPath = "/_api/manifest"
+const reloadPath = "/_reload"
+const readyPath = "/_health/ready"
+
+const gitVersionPrefix = "/_v/"
+
+// Refs of the content repo that may be served under /_v/<ref>/, e.g. "v1.0.0,v2.0.0,main"
+func getGitRefs() []string {
+	var refs []string
+	for _, ref := range strings.Split(getEnv("GIT_REFS", ""), ",") {
+		if ref = strings.TrimSpace(ref); ref != "" {
+			refs = append(refs, ref)
+		}
+	}
+	if _, err := exec.LookPath("git"); len(refs) > 0 && err != nil {
+		fmt.Println("⇨ warning: GIT_REFS needs git installed, /_v/ won't serve anything without it")
+	}
+	return refs
+}
+
+var gitRefs = getGitRefs()
+
+// Files read from git by ref and path, cleared on reindex since branches move
+var gitBlobs = newBodyCache(getEnvSize("GIT_CACHE_MEMORY", 32*1024*1024))
+
+// git processes running at once, so a crawl of /_v/ can't fork without bound
+var gitSlots = make(chan struct{}, max(getEnvInt("GIT_CONCURRENCY", 4), 1))
+
+// Read a file as of a git ref, relative to the first public dir within its repo, failing for directories
+func gitShow(ref string, relPath string) ([]byte, error) {
+	key := ref + ":" + relPath
+	if blob, exists := gitBlobs.Get(key); exists {
+		return blob.Plain, nil
+	}
+	gitSlots <- struct{}{}
+	dat, err := exec.Command("git", "-C", publicDirs[0], "cat-file", "blob", ref+":./"+relPath).Output()
+	<-gitSlots
+	if err != nil {
+		return nil, err
+	}
+	gitBlobs.Put(key, Content{Plain: dat})
+	return dat, nil
+}
+
+// Serve /_v/<ref>/<path> from the public dir as of an allowed git ref, without extracting it
+func handleGitVersion(ctx *fasthttp.RequestCtx) {
+	ref, urlPath, _ := strings.Cut(strings.TrimPrefix(string(ctx.Path()), gitVersionPrefix), "/")
+	urlPath = path.Clean("/" + urlPath)
+	if !slices.Contains(gitRefs, ref) || isHidden(urlPath) || isExcluded(urlPath) {
+		serveError(ctx, fasthttp.StatusNotFound)
+		return
+	}
+	candidates := []string{strings.TrimPrefix(urlPath, "/")}
+	for _, indexFile := range indexFiles {
+		candidates = append(candidates, strings.TrimPrefix(path.Join(urlPath, indexFile), "/"))
+	}
+	for _, candidate := range candidates {
+		dat, err := gitShow(ref, candidate)
+		if err != nil {
+			continue
+		}
+		ctx.Response.Header.Set("Content-Type", getMimetype(strings.ToLower(path.Ext(candidate))))
+		ctx.Response.Header.Set("Server", "nano-web")
+		ctx.SetBody(dat)
+		return
+	}
+	serveError(ctx, fasthttp.StatusNotFound)
+}
+
+// Ready once the first generation of routes is fully built
+func handleReady(ctx *fasthttp.RequestCtx) {
+	if !populated.Load() {
+		ctx.Response.Header.Set("Retry-After", "1")
+		ctx.Error("Service Unavailable", fasthttp.StatusServiceUnavailable)
+		return
+	}
+	ctx.SetContentType("text/plain")
+	if degraded.Load() {
+		ctx.SetBodyString("DEGRADED")
+		return
+	}
+	ctx.SetBodyString("OK")
+}
+
+// Respond with the sorted url paths being served, for `nano-web selftest` to sample from
+func handleManifest(ctx *fasthttp.RequestCtx) {
+	urlPaths := []string{}
+	routesLock.RLock()
+	for urlPath, route := range routes {
+		if route.Status == 0 {
+			urlPaths = append(urlPaths, urlPath)
+		}
+	}
+	routesLock.RUnlock()
+	sort.Strings(urlPaths)
+	dat, err := json.Marshal(urlPaths)
+	if err != nil {
+		ctx.Error("Internal Server Error", fasthttp.StatusInternalServerError)
+		return
+	}
+	ctx.SetContentType("application/json")
+	ctx.SetBody(dat)
+}
+
+func handleDiff(ctx *fasthttp.RequestCtx) {
+	dat, err := json.Marshal(diffGenerations())
+	if err != nil {
+		ctx.Error("Internal Server Error", fasthttp.StatusInternalServerError)
+		return
+	}
+	ctx.SetContentType("application/json")
+	ctx.SetBody(dat)
+}
+
+// Reindex on request, or with ?dry_run=true only report what a reindex would change
+func handleReloadRequest(ctx *fasthttp.RequestCtx) {
+	if !ctx.IsPost() {
+		ctx.Response.Header.Set("Allow", "POST")
+		ctx.Error("Method Not Allowed", fasthttp.StatusMethodNotAllowed)
+		return
+	}
+	if productionMode && !ctx.QueryArgs().GetBool("dry_run") {
+		ctx.Error("Forbidden: production mode only reloads on SIGHUP", fasthttp.StatusForbidden)
+		return
+	}
+	dat, err := json.Marshal(reindexRoutes(ctx.QueryArgs().GetBool("dry_run")))
+	if err != nil {
+		ctx.Error("Internal Server Error", fasthttp.StatusInternalServerError)
+		return
+	}
+	ctx.SetContentType("application/json")
+	ctx.SetBody(dat)
+}
+
+// Ask a running node for a dry run reload of its public dir, exiting non-zero if it would
+// introduce conflicts or template errors, for `nano-web validate --against-running <url>`
+func validateAgainstRunning(args []string) {
+	flags := flag.NewFlagSet("validate", flag.ExitOnError)
+	against := flags.String("against-running", "", "url of the running node, e.g. http://localhost:80")
+	flags.Parse(args)
+	if *against == "" {
+		fmt.Println("⇨ usage: nano-web validate --against-running <url>")
+		os.Exit(2)
+	}
+	req, err := http.NewRequest(http.MethodPost, strings.TrimSuffix(*against, "/")+reloadPath+"?dry_run=true", nil)
+	if err != nil {
+		fmt.Println("⇨ error:", err)
+		os.Exit(2)
+	}
+	req.Header.Set("Authorization", "Bearer "+adminToken)
+	res, err := http.DefaultClient.Do(req)
+	if err != nil {
+		fmt.Println("⇨ error:", err)
+		os.Exit(2)
+	}
+	defer res.Body.Close()
+	if res.StatusCode != http.StatusOK {
+		fmt.Println("⇨ error: running node responded", res.Status)
+		os.Exit(2)
+	}
+	var report ReloadReport
+	if err := json.NewDecoder(res.Body).Decode(&report); err != nil {
+		fmt.Println("⇨ error:", err)
+		os.Exit(2)
+	}
+	dat, _ := json.MarshalIndent(report, "", "  ")
+	fmt.Println(string(dat))
+	if len(report.Conflicts) > 0 || len(report.TemplateErrors) > 0 {
+		os.Exit(1)
+	}
+}
+
+type CapturedRequest struct {
+	Time           int64  `json:"time"`
+	Path           string `json:"path"`
+	AcceptEncoding string `json:"acceptEncoding,omitempty"`
+	UserAgent      string `json:"userAgent,omitempty"`
+}
+
+var captureSampleRate = getEnvFloat("CAPTURE_SAMPLE_RATE", 1)
+
+// What's kept of captured query strings: nothing with "drop", the default since queries often
+// carry tokens, only their keys with "redact", or all of it with "keep"
+func getCaptureQuery() string {
+	mode := getEnv("CAPTURE_QUERY", "drop")
+	if mode != "drop" && mode != "redact" && mode != "keep" {
+		fmt.Println("⇨ invalid CAPTURE_QUERY:", mode)
+		os.Exit(-1)
+	}
+	return mode
+}
+
+var captureQuery = getCaptureQuery()
+
+func capturedUri(ctx *fasthttp.RequestCtx) string {
+	uri := string(ctx.URI().PathOriginal())
+	switch captureQuery {
+	case "keep":
+		return string(ctx.RequestURI())
+	case "redact":
+		var args []string
+		ctx.QueryArgs().VisitAll(func(key []byte, value []byte) {
+			args = append(args, url.QueryEscape(string(key))+"=redacted")
+		})
+		if len(args) > 0 {
+			uri += "?" + strings.Join(args, "&")
+		}
+	}
+	return uri
+}
+
+// A file records are appended to as JSON lines, flushed every second
+type JsonLines struct {
+	lock   sync.Mutex
+	writer *bufio.Writer
+}
+
+func newJsonLines(path string) *JsonLines {
+	// Only readable by the server's user, since requests can reveal what visitors look at
+	file, err := os.OpenFile(path, os.O_CREATE|os.O_APPEND|os.O_WRONLY, 0600)
+	if err != nil {
+		fmt.Println("⇨ error opening", path+":", err)
+		os.Exit(-1)
+	}
+	lines := &JsonLines{writer: bufio.NewWriter(file)}
+	go func() {
+		for range time.Tick(time.Second) {
+			lines.lock.Lock()
+			lines.writer.Flush()
+			lines.lock.Unlock()
+		}
+	}()
+	return lines
+}
+
+func (lines *JsonLines) write(record any) {
+	dat, _ := json.Marshal(record)
+	lines.lock.Lock()
+	defer lines.lock.Unlock()
+	lines.writer.Write(append(dat, '\n'))
+}
+
+// Sampled requests written to CAPTURE_FILE, for replaying with `nano-web bench`. Wrapped inside
+// the host and auth checks, so requests this node refuses aren't captured.
+func withCapture(next fasthttp.RequestHandler, capture *JsonLines) fasthttp.RequestHandler {
+	return func(ctx *fasthttp.RequestCtx) {
+		if rand.Float64() < captureSampleRate {
+			capture.write(CapturedRequest{
+				Time:           ctx.Time().UnixMilli(),
+				Path:           capturedUri(ctx),
+				AcceptEncoding: string(ctx.Request.Header.Peek("Accept-Encoding")),
+				UserAgent:      string(ctx.UserAgent()),
+			})
+		}
+		next(ctx)
+	}
+}
+
+type AccessLogEntry struct {
+	Time     int64  `json:"time"`
+	Path     string `json:"path"`
+	Status   int    `json:"status"`
+	Bytes    int    `json:"bytes"`
+	Encoding string `json:"encoding"`
+	Trace    string `json:"trace,omitempty"`
+}
+
+// A secondary deployment requests are copied to for testing under real traffic, e.g.
+// https://canary.example.com
+var mirrorUrl = strings.TrimSuffix(getEnv("MIRROR_URL", ""), "/")
+var mirrorPercent = getEnvFloat("MIRROR_PERCENT", 100)
+var mirrorClient = &http.Client{Timeout: 10 * time.Second}
+
+// Mirrors in flight, beyond which they're dropped rather than piling up behind a slow target
+var mirrorSlots = make(chan struct{}, max(getEnvInt("MIRROR_CONCURRENCY", 64), 1))
+
+type MirrorPrefix struct {
+	Prefix  string
+	Percent float64
+}
+
+// Comma separated MIRROR_PREFIXES of url prefixes to mirror, each optionally with its own
+// percent, e.g. "/docs=10,/blog", longest first. Without any every path is mirrored.
+func getMirrorPrefixes() []MirrorPrefix {
+	var prefixes []MirrorPrefix
+	for _, mapping := range strings.Split(getEnv("MIRROR_PREFIXES", ""), ",") {
+		if strings.TrimSpace(mapping) == "" {
+			continue
+		}
+		prefix, percent, found := strings.Cut(strings.TrimSpace(mapping), "=")
+		value, err := strconv.ParseFloat(percent, 64)
+		if !found {
+			value, err = mirrorPercent, nil
+		}
+		if err != nil || !strings.HasPrefix(prefix, "/") {
+			fmt.Println("⇨ invalid mapping in MIRROR_PREFIXES:", mapping)
+			os.Exit(-1)
+		}
+		prefixes = append(prefixes, MirrorPrefix{prefix, value})
+	}
+	sort.SliceStable(prefixes, func(i, j int) bool { return len(prefixes[i].Prefix) > len(prefixes[j].Prefix) })
+	return prefixes
+}
+
+var mirrorPrefixes = getMirrorPrefixes()
+
+// The percent of requests for a path that are mirrored
+func mirrorPercentFor(urlPath string) float64 {
+	if len(mirrorPrefixes) == 0 {
+		return mirrorPercent
+	}
+	for _, prefix := range mirrorPrefixes {
+		if strings.HasPrefix(urlPath, prefix.Prefix) {
+			return prefix.Percent
+		}
+	}
+	return 0
+}
+
+// Credentials that mustn't reach the mirror target, which is often a less trusted environment
+var mirrorStripHeaders = map[string]bool{"Authorization": true, "Proxy-Authorization": true, "Cookie": true}
+
+// Whether a request may be mirrored: only reads, and never the admin endpoints or a login callback
+func shouldMirror(ctx *fasthttp.RequestCtx) bool {
+	urlPath := string(ctx.Path())
+	if !ctx.IsGet() && !ctx.IsHead() {
+		return false
+	}
+	if slices.Contains([]string{diffPath, verifyPath, manifestPath, reloadPath, metricsPath, oidcCallbackPath}, urlPath) {
+		return false
+	}
+	return rand.Float64()*100 < mirrorPercentFor(urlPath)
+}
+
+// Copy a request to MIRROR_URL in the background, discarding the response
+func mirrorRequest(ctx *fasthttp.RequestCtx) {
+	select {
+	case mirrorSlots <- struct{}{}:
+	default:
+		return
+	}
+	request, err := http.NewRequest(string(ctx.Method()), mirrorUrl+string(ctx.RequestURI()), bytes.NewReader(append([]byte{}, ctx.PostBody()...)))
+	if err != nil {
+		<-mirrorSlots
+		return
+	}
+	ctx.Request.Header.VisitAll(func(name []byte, value []byte) {
+		if !mirrorStripHeaders[http.CanonicalHeaderKey(string(name))] {
+			request.Header.Add(string(name), string(value))
+		}
+	})
+	go func() {
+		defer func() { <-mirrorSlots }()
+		response, err := mirrorClient.Do(request)
+		if err != nil {
+			return
+		}
+		io.Copy(io.Discard, response.Body)
+		response.Body.Close()
+	}()
+}
+
+// Mirror a percentage of requests without affecting the response served. Wrapped inside the host
+// and auth checks, so only requests this node would serve are mirrored.
+func withMirror(next fasthttp.RequestHandler) fasthttp.RequestHandler {
+	return func(ctx *fasthttp.RequestCtx) {
+		if shouldMirror(ctx) {
+			mirrorRequest(ctx)
+		}
+		next(ctx)
+	}
+}
+
+// Every response written to ACCESS_LOG, for `nano-web report`
+func withAccessLog(next fasthttp.RequestHandler, accessLog *JsonLines) fasthttp.RequestHandler {
+	return func(ctx *fasthttp.RequestCtx) {
+		next(ctx)
+		size := len(ctx.Response.Body())
+		if ctx.Response.IsBodyStream() {
+			size = max(ctx.Response.Header.ContentLength(), 0)
+		}
+		encoding := string(ctx.Response.Header.Peek("Content-Encoding"))
+		if encoding == "" {
+			encoding = "identity"
+		}
+		traceId, _ := ctx.UserValue("traceId").(string)
+		accessLog.write(AccessLogEntry{
+			Time:     ctx.Time().UnixMilli(),
+			Path:     string(ctx.Path()),
+			Status:   ctx.Response.StatusCode(),
+			Bytes:    size,
+			Encoding: encoding,
+			Trace:    traceId,
+		})
+	}
+}
+
+type Count struct {
+	Name  string `json:"name"`
+	Count int64  `json:"count"`
+}
+
+type Report struct {
+	Requests           int64   `json:"requests"`
+	HottestRoutes      []Count `json:"hottestRoutes"`
+	BytesByDirectory   []Count `json:"bytesByDirectory"`
+	RequestsByEncoding []Count `json:"requestsByEncoding"`
+	NotFoundOffenders  []Count `json:"notFoundOffenders"`
+}
+
+// The largest counts first, up to a limit
+func topCounts(counts map[string]int64, limit int) []Count {
+	top := make([]Count, 0, len(counts))
+	for name, count := range counts {
+		top = append(top, Count{name, count})
+	}
+	sort.Slice(top, func(i, j int) bool {
+		if top[i].Count != top[j].Count {
+			return top[i].Count > top[j].Count
+		}
+		return top[i].Name < top[j].Name
+	})
+	if len(top) > limit {
+		top = top[:limit]
+	}
+	return top
+}
+
+// Summarise an access log, for `nano-web report --from access.log [--format text|json] [--top 10]`
+func report(args []string) {
+	flags := flag.NewFlagSet("report", flag.ExitOnError)
+	from := flags.String("from", getEnv("ACCESS_LOG", ""), "access log written with ACCESS_LOG")
+	format := flags.String("format", "text", "text or json")
+	limit := flags.Int("top", 10, "entries per list")
+	flags.Parse(args)
+	file, err := os.Open(*from)
+	if err != nil {
+		fmt.Println("⇨ usage: nano-web report --from access.log [--format text|json] [--top 10]:", err)
+		os.Exit(2)
+	}
+	defer file.Close()
+
+	var requests int64
+	routeCounts := make(map[string]int64)
+	directoryBytes := make(map[string]int64)
+	encodingCounts := make(map[string]int64)
+	notFound := make(map[string]int64)
+	scanner := bufio.NewScanner(file)
+	for scanner.Scan() {
+		var entry AccessLogEntry
+		if json.Unmarshal(scanner.Bytes(), &entry) != nil {
+			continue
+		}
+		requests++
+		if entry.Status == fasthttp.StatusNotFound {
+			notFound[entry.Path]++
+			continue
+		}
+		routeCounts[entry.Path]++
+		directoryBytes[path.Dir(entry.Path)] += int64(entry.Bytes)
+		encodingCounts[entry.Encoding]++
+	}
+	result := Report{
+		Requests:           requests,
+		HottestRoutes:      topCounts(routeCounts, *limit),
+		BytesByDirectory:   topCounts(directoryBytes, *limit),
+		RequestsByEncoding: topCounts(encodingCounts, *limit),
+		NotFoundOffenders:  topCounts(notFound, *limit),
+	}
+	if *format == "json" {
+		dat, _ := json.MarshalIndent(result, "", "  ")
+		fmt.Println(string(dat))
+		return
+	}
+	fmt.Println(requests, "requests")
+	for _, section := range []struct {
+		title  string
+		counts []Count
+	}{
+		{"Hottest routes", result.HottestRoutes},
+		{"Bytes served by directory", result.BytesByDirectory},
+		{"Requests by encoding", result.RequestsByEncoding},
+		{"Top 404s", result.NotFoundOffenders},
+	} {
+		fmt.Println("\n" + section.title)
+		for _, count := range section.counts {
+			fmt.Printf("%12d  %s\n", count.Count, count.Name)
+		}
+	}
+}
+
+// Redirect, header, status and error page rules converted from a legacy server config
+type ImportedRules struct {
+	RedirectFrom map[string][]string
+	Headers      map[string]map[string]string
+	Statuses     map[string]int
+	ErrorPages   map[int]string
+	Skipped      []string
+}
+
+// Record a redirect as a redirect_from alias of the file it points to, or a gone path as a
+// status override. Only permanent redirects to local paths have an equivalent.
+func (rules *ImportedRules) redirect(from string, to string, status int) {
+	switch {
+	case status == fasthttp.StatusGone:
+		rules.Statuses[from] = status
+	case status != fasthttp.StatusMovedPermanently && status != fasthttp.StatusPermanentRedirect:
+		rules.Skipped = append(rules.Skipped, fmt.Sprintf("%s → %s: status %d", from, to, status))
+	case !strings.HasPrefix(to, "/") || strings.ContainsAny(to, "?$"):
+		rules.Skipped = append(rules.Skipped, fmt.Sprintf("%s → %s: not a local path", from, to))
+	default:
+		rules.RedirectFrom[to] = append(rules.RedirectFrom[to], from)
+	}
+}
+
+func (rules *ImportedRules) header(urlPath string, name string, value string) {
+	if rules.Headers[urlPath] == nil {
+		rules.Headers[urlPath] = make(map[string]string)
+	}
+	rules.Headers[urlPath][name] = value
+}
+
+var nginxLocation = regexp.MustCompile(`location\s+(=|\^~|~\*?)?\s*(\S+)\s*\{([^{}]*)\}`)
+var nginxReturn = regexp.MustCompile(`return\s+(\d{3})(?:\s+(\S+?))?\s*;`)
+var nginxAddHeader = regexp.MustCompile(`add_header\s+(\S+)\s+("[^"]*"|'[^']*'|\S+?)(?:\s+always)?\s*;`)
+var nginxRewrite = regexp.MustCompile(`rewrite\s+\^(/[^\s$^*+?()\[\]|\\]*)\$\s+(\S+)\s+(permanent|redirect)\s*;`)
+var nginxErrorPage = regexp.MustCompile(`error_page\s+([\d\s]+?)\s+(/\S+?)\s*;`)
+
+// Convert exact locations that return or add headers, prefix locations that add headers,
+// literal rewrites and error pages. Regex locations have no equivalent.
+func importNginx(dat string, files []string, rules *ImportedRules) {
+	for _, match := range nginxLocation.FindAllStringSubmatch(dat, -1) {
+		modifier, location, body := match[1], match[2], match[3]
+		if strings.HasPrefix(modifier, "~") {
+			rules.Skipped = append(rules.Skipped, "location "+modifier+" "+location+": regex location")
+			continue
+		}
+		for _, ret := range nginxReturn.FindAllStringSubmatch(body, -1) {
+			status, _ := strconv.Atoi(ret[1])
+			if modifier != "=" {
+				rules.Skipped = append(rules.Skipped, "location "+location+": return in a prefix location")
+				continue
+			}
+			rules.redirect(location, ret[2], status)
+		}
+		for _, header := range nginxAddHeader.FindAllStringSubmatch(body, -1) {
+			value := unquote(header[2])
+			if modifier == "=" {
+				rules.header(location, header[1], value)
+				continue
+			}
+			for _, path := range files {
+				if urlPath := getUrlPath(path); strings.HasPrefix(urlPath, location) {
+					rules.header(urlPath, header[1], value)
+				}
+			}
+		}
+	}
+	for _, match := range nginxRewrite.FindAllStringSubmatch(dat, -1) {
+		status := fasthttp.StatusMovedPermanently
+		if match[3] == "redirect" {
+			status = fasthttp.StatusFound
+		}
+		rules.redirect(match[1], match[2], status)
+	}
+	for _, match := range nginxErrorPage.FindAllStringSubmatch(dat, -1) {
+		for _, code := range strings.Fields(match[1]) {
+			status, _ := strconv.Atoi(code)
+			rules.ErrorPages[status] = match[2]
+		}
+	}
+}
+
+// Split an Apache directive into its words, keeping quoted values together
+func directiveFields(line string) []string {
+	var fields []string
+	for line = strings.TrimSpace(line); line != ""; line = strings.TrimSpace(line) {
+		end := strings.IndexAny(line, " \t")
+		if line[0] == '"' || line[0] == '\'' {
+			end = strings.IndexByte(line[1:], line[0]) + 2
+		}
+		if end <= 0 || end > len(line) {
+			end = len(line)
+		}
+		fields = append(fields, unquote(line[:end]))
+		line = line[end:]
+	}
+	return fields
+}
+
+// Convert Redirect, RedirectPermanent and ErrorDocument directives, and headers set within
+// <Files> sections, which apply to every file of that name
+func importHtaccess(dat string, files []string, rules *ImportedRules) {
+	redirectStatuses := map[string]int{"permanent": 301, "temp": 302, "seeother": 303, "gone": 410}
+	section := ""
+	for _, line := range strings.Split(dat, "\n") {
+		fields := directiveFields(line)
+		if len(fields) == 0 || strings.HasPrefix(fields[0], "#") {
+			continue
+		}
+		switch directive := strings.ToLower(fields[0]); {
+		case directive == "<files" && len(fields) > 1:
+			section = strings.TrimSuffix(fields[1], ">")
+		case directive == "</files>":
+			section = ""
+		case directive == "redirect" || directive == "redirectpermanent":
+			args := fields[1:]
+			status := fasthttp.StatusFound
+			if directive == "redirectpermanent" {
+				status = fasthttp.StatusMovedPermanently
+			} else if len(args) > 0 && !strings.HasPrefix(args[0], "/") {
+				status = redirectStatuses[strings.ToLower(args[0])]
+				if code, err := strconv.Atoi(args[0]); err == nil {
+					status = code
+				}
+				args = args[1:]
+			}
+			if len(args) == 0 {
+				continue
+			}
+			args = append(args, "")
+			rules.redirect(args[0], args[1], status)
+		case directive == "errordocument" && len(fields) == 3 && strings.HasPrefix(fields[2], "/"):
+			if status, err := strconv.Atoi(fields[1]); err == nil {
+				rules.ErrorPages[status] = fields[2]
+			}
+		case directive == "header" && len(fields) >= 4:
+			args := fields[1:]
+			if strings.ToLower(args[0]) == "always" {
+				args = args[1:]
+			}
+			if len(args) < 3 || strings.ToLower(args[0]) != "set" {
+				rules.Skipped = append(rules.Skipped, strings.TrimSpace(line)+": only Header set is supported")
+			} else if section == "" {
+				rules.Skipped = append(rules.Skipped, strings.TrimSpace(line)+": applies to every file")
+			} else {
+				for _, path := range files {
+					if filepath.Base(path) == section {
+						rules.header(getUrlPath(path), args[1], args[2])
+					}
+				}
+			}
+		default:
+			if directive != "<ifmodule" && directive != "</ifmodule>" {
+				rules.Skipped = append(rules.Skipped, strings.TrimSpace(line))
+			}
+		}
+	}
+}
+
+// Print the sidecars the rules become, merged into any the files already have, then the
+// STATUS_OVERRIDES and ERROR_PAGES settings
+func (rules *ImportedRules) print() {
+	sidecars := make(map[string]map[string]any)
+	sidecar := func(urlPath string, rule string) map[string]any {
+		path, exists := resolveFile(urlPath)
+		if !exists {
+			rules.Skipped = append(rules.Skipped, rule+": no file at "+urlPath)
 			return nil
 		}
-		urlPath := strings.Replace(path, "public", "", 1)
+		if sidecars[path] == nil {
+			meta := make(map[string]any)
+			if dat, err := os.ReadFile(path + metaSuffix); err == nil {
+				json.Unmarshal(dat, &meta)
+			}
+			sidecars[path] = meta
+		}
+		return sidecars[path]
+	}
+	for _, target := range sortedKeys(rules.RedirectFrom) {
+		if meta := sidecar(target, strings.Join(rules.RedirectFrom[target], ", ")+" → "+target); meta != nil {
+			existing, _ := meta["redirect_from"].([]any)
+			for _, from := range rules.RedirectFrom[target] {
+				existing = append(existing, from)
+			}
+			meta["redirect_from"] = existing
+		}
+	}
+	for _, urlPath := range sortedKeys(rules.Headers) {
+		if meta := sidecar(urlPath, "headers for "+urlPath); meta != nil {
+			headers, _ := meta["headers"].(map[string]any)
+			if headers == nil {
+				headers = make(map[string]any)
+			}
+			for name, value := range rules.Headers[urlPath] {
+				headers[name] = value
+			}
+			meta["headers"] = headers
+		}
+	}
+	for _, path := range sortedKeys(sidecars) {
+		dat, _ := json.MarshalIndent(sidecars[path], "", "  ")
+		fmt.Println("# " + path + metaSuffix)
+		fmt.Println(string(dat))
+		fmt.Println()
+	}
+	var overrides []string
+	for _, urlPath := range sortedKeys(rules.Statuses) {
+		overrides = append(overrides, urlPath+"="+strconv.Itoa(rules.Statuses[urlPath]))
+	}
+	if len(overrides) > 0 {
+		fmt.Println("STATUS_OVERRIDES=" + strings.Join(overrides, ","))
+	}
+	var pages []string
+	for _, status := range sortedKeys(rules.ErrorPages) {
+		pages = append(pages, strconv.Itoa(status)+"="+rules.ErrorPages[status])
+	}
+	if len(pages) > 0 {
+		fmt.Println("ERROR_PAGES=" + strings.Join(pages, ","))
+	}
+	for _, skipped := range rules.Skipped {
+		fmt.Println("# skipped:", skipped)
+	}
+}
+
+// Convert a legacy nginx config or .htaccess into sidecars and settings, for
+// `nano-web import [--format nginx|htaccess] <file>`
+func importConfig(args []string) {
+	flags := flag.NewFlagSet("import", flag.ExitOnError)
+	format := flags.String("format", "", "nginx or htaccess, by default guessed from the file name")
+	flags.Parse(args)
+	if flags.NArg() != 1 {
+		fmt.Println("⇨ usage: nano-web import [--format nginx|htaccess] <file>")
+		os.Exit(2)
+	}
+	dat, err := os.ReadFile(flags.Arg(0))
+	if err != nil {
+		fmt.Println("⇨ error:", err)
+		os.Exit(2)
+	}
+	if *format == "" {
+		*format = "nginx"
+		if strings.HasSuffix(flags.Arg(0), ".htaccess") {
+			*format = "htaccess"
+		}
+	}
+	rules := &ImportedRules{
+		RedirectFrom: make(map[string][]string),
+		Headers:      make(map[string]map[string]string),
+		Statuses:     make(map[string]int),
+		ErrorPages:   make(map[int]string),
+	}
+	files := collectFiles()
+	switch *format {
+	case "nginx":
+		importNginx(string(dat), files, rules)
+	case "htaccess":
+		importHtaccess(string(dat), files, rules)
+	default:
+		fmt.Println("⇨ unknown format", *format)
+		os.Exit(2)
+	}
+	rules.print()
+}
+
+// Replay a capture against a server, either with the original timing between requests or as
+// fast as the concurrency allows, and report throughput and latency, for
+// `nano-web bench --replay capture.jsonl [--speed original|max] [--concurrency 16] <url>`
+func bench(args []string) {
+	flags := flag.NewFlagSet("bench", flag.ExitOnError)
+	replay := flags.String("replay", "", "capture file of requests to replay")
+	speed := flags.String("speed", "max", "original to keep the captured timing, max to send as fast as possible")
+	concurrency := flags.Int("concurrency", 16, "requests in flight at once at max speed")
+	flags.Parse(args)
+	if *replay == "" || flags.NArg() < 1 || (*speed != "original" && *speed != "max") {
+		fmt.Println("⇨ usage: nano-web bench --replay capture.jsonl [--speed original|max] [--concurrency 16] <url>")
+		os.Exit(2)
+	}
+	base := strings.TrimSuffix(flags.Arg(0), "/")
+	dat, err := os.ReadFile(*replay)
+	if err != nil {
+		fmt.Println("⇨ error:", err)
+		os.Exit(2)
+	}
+	var requests []CapturedRequest
+	for _, line := range strings.Split(string(dat), "\n") {
+		var request CapturedRequest
+		if json.Unmarshal([]byte(line), &request) == nil {
+			requests = append(requests, request)
+		}
+	}
+	if len(requests) == 0 {
+		fmt.Println("⇨ no requests in", *replay)
+		os.Exit(2)
+	}
+
+	client := &fasthttp.Client{}
+	durations := make([]time.Duration, len(requests))
+	var errorCount atomic.Int64
+	send := func(i int) {
+		req := fasthttp.AcquireRequest()
+		res := fasthttp.AcquireResponse()
+		defer fasthttp.ReleaseRequest(req)
+		defer fasthttp.ReleaseResponse(res)
+		req.SetRequestURI(base + requests[i].Path)
+		req.Header.Set("Accept-Encoding", requests[i].AcceptEncoding)
+		req.Header.SetUserAgent(requests[i].UserAgent)
+		start := time.Now()
+		if err := client.Do(req, res); err != nil || res.StatusCode() >= 500 {
+			errorCount.Add(1)
+		}
+		durations[i] = time.Since(start)
+	}
+
+	var wg sync.WaitGroup
+	start := time.Now()
+	if *speed == "original" {
+		for i := range requests {
+			time.Sleep(time.Until(start.Add(time.Duration(requests[i].Time-requests[0].Time) * time.Millisecond)))
+			wg.Add(1)
+			go func(i int) {
+				defer wg.Done()
+				send(i)
+			}(i)
+		}
+	} else {
+		queue := make(chan int)
+		for w := 0; w < *concurrency; w++ {
+			wg.Add(1)
+			go func() {
+				defer wg.Done()
+				for i := range queue {
+					send(i)
+				}
+			}()
+		}
+		for i := range requests {
+			queue <- i
+		}
+		close(queue)
+	}
+	wg.Wait()
+	elapsed := time.Since(start)
+
+	sort.Slice(durations, func(i, j int) bool { return durations[i] < durations[j] })
+	percentile := func(p float64) time.Duration {
+		return durations[int(p*float64(len(durations)-1))]
+	}
+	fmt.Printf("⇨ %d requests in %s (%.0f/s), %d errors\n", len(requests), elapsed.Round(time.Millisecond),
+		float64(len(requests))/elapsed.Seconds(), errorCount.Load())
+	fmt.Printf("⇨ latency p50 %s, p90 %s, p99 %s, max %s\n", percentile(0.5), percentile(0.9), percentile(0.99),
+		durations[len(durations)-1])
+}
+
+type headerFlags []string
+
+func (h *headerFlags) String() string {
+	return strings.Join(*h, ",")
+}
+
+func (h *headerFlags) Set(value string) error {
+	*h = append(*h, value)
+	return nil
+}
+
+var selftestClient = &http.Client{
+	Timeout: 10 * time.Second,
+	// Compressed responses are checked as sent, and redirects are responses in their own right
+	Transport: &http.Transport{DisableCompression: true},
+	CheckRedirect: func(req *http.Request, via []*http.Request) error {
+		return http.ErrUseLastResponse
+	},
+}
+
+func selftestFetch(target string, headers map[string]string) (*http.Response, []byte, error) {
+	req, err := http.NewRequest(http.MethodGet, target, nil)
+	if err != nil {
+		return nil, nil, err
+	}
+	for name, value := range headers {
+		req.Header.Set(name, value)
+	}
+	res, err := selftestClient.Do(req)
+	if err != nil {
+		return nil, nil, err
+	}
+	defer res.Body.Close()
+	body, err := io.ReadAll(res.Body)
+	return res, body, err
+}
+
+// The security headers every response should have, usually set by sidecars or a proxy in front:
+// nosniff, framing rules on HTML, and HSTS over https
+func missingSecurityHeaders(base string, res *http.Response) []string {
+	var missing []string
+	if !strings.EqualFold(res.Header.Get("X-Content-Type-Options"), "nosniff") {
+		missing = append(missing, "X-Content-Type-Options: nosniff")
+	}
+	if strings.HasPrefix(res.Header.Get("Content-Type"), "text/html") && res.Header.Get("X-Frame-Options") == "" &&
+		!strings.Contains(res.Header.Get("Content-Security-Policy"), "frame-ancestors") {
+		missing = append(missing, "X-Frame-Options or a Content-Security-Policy with frame-ancestors")
+	}
+	if strings.HasPrefix(base, "https://") && res.Header.Get("Strict-Transport-Security") == "" {
+		missing = append(missing, "Strict-Transport-Security")
+	}
+	return missing
+}
+
+// Check one path of a running instance, returning the problems found
+func selftestPath(base string, urlPath string, requiredHeaders []string, securityHeaders bool) []string {
+	var problems []string
+	res, plain, err := selftestFetch(base+urlPath, map[string]string{"Accept-Encoding": "identity"})
+	if err != nil {
+		return []string{err.Error()}
+	}
+	if res.StatusCode >= 300 && res.StatusCode < 400 {
+		if res.Header.Get("Location") == "" {
+			problems = append(problems, fmt.Sprintf("status %d without a Location", res.StatusCode))
+		}
+		return problems
+	}
+	if res.StatusCode != http.StatusOK {
+		return []string{"status " + res.Status}
+	}
+	if encoding := res.Header.Get("Content-Encoding"); encoding != "" {
+		problems = append(problems, "Content-Encoding "+encoding+" when only identity is accepted")
+	}
+	for _, name := range requiredHeaders {
+		if res.Header.Get(name) == "" {
+			problems = append(problems, "missing "+name+" header")
+		}
+	}
+	if securityHeaders {
+		for _, missing := range missingSecurityHeaders(base, res) {
+			problems = append(problems, "missing "+missing)
+		}
+	}
+
+	for _, encoding := range []string{"br", "gzip"} {
+		res, body, err := selftestFetch(base+urlPath, map[string]string{"Accept-Encoding": encoding})
+		if err != nil {
+			problems = append(problems, encoding+": "+err.Error())
+			continue
+		}
+		var decoded []byte
+		switch res.Header.Get("Content-Encoding") {
+		case "":
+			decoded = body
+		case "br":
+			decoded, err = io.ReadAll(brotli.NewReader(bytes.NewReader(body)))
+		case "gzip":
+			reader, gzipErr := gzip.NewReader(bytes.NewReader(body))
+			if err = gzipErr; err == nil {
+				decoded, err = io.ReadAll(reader)
+			}
+		default:
+			problems = append(problems, encoding+": unexpected Content-Encoding "+res.Header.Get("Content-Encoding"))
+			continue
+		}
+		if err != nil || !bytes.Equal(decoded, plain) {
+			problems = append(problems, encoding+": body doesn't decode to the uncompressed response")
+		}
+		if res.Header.Get("Content-Encoding") != "" && res.Header.Get("Content-Encoding") != encoding {
+			problems = append(problems, encoding+": responded with "+res.Header.Get("Content-Encoding")+", which wasn't accepted")
+		}
+	}
+
+	if lastModified := res.Header.Get("Last-Modified"); lastModified != "" {
+		res, body, err := selftestFetch(base+urlPath, map[string]string{"If-Modified-Since": lastModified})
+		if err != nil {
+			problems = append(problems, "If-Modified-Since: "+err.Error())
+		} else if res.StatusCode != http.StatusNotModified {
+			problems = append(problems, fmt.Sprintf("If-Modified-Since with the current Last-Modified got %d, not 304", res.StatusCode))
+		} else if len(body) > 0 {
+			problems = append(problems, "304 with a body")
+		}
+	}
+	return problems
+}
+
+// A random sample of the url paths a running instance serves, from its manifest endpoint
+func selftestSample(base string, token string, sample int) ([]string, error) {
+	res, body, err := selftestFetch(base+manifestPath, map[string]string{"Authorization": "Bearer " + token})
+	if err != nil {
+		return nil, err
+	}
+	if res.StatusCode != http.StatusOK {
+		return nil, errors.New("manifest responded " + res.Status)
+	}
+	var urlPaths []string
+	if err := json.Unmarshal(body, &urlPaths); err != nil {
+		return nil, err
+	}
+	rand.Shuffle(len(urlPaths), func(i, j int) { urlPaths[i], urlPaths[j] = urlPaths[j], urlPaths[i] })
+	if sample > 0 && len(urlPaths) > sample {
+		urlPaths = urlPaths[:sample]
+	}
+	return urlPaths, nil
+}
+
+// Smoke test a running instance after a deploy, exiting non-zero on any problems, for
+// `nano-web selftest http://host:port [path...]`. Without paths, a sample is read from the manifest.
+func selftest(args []string) {
+	flags := flag.NewFlagSet("selftest", flag.ExitOnError)
+	pathsFile := flags.String("paths", "", "file of url paths to check, one per line")
+	sample := flags.Int("sample", 20, "url paths to sample from the manifest when none are given, 0 for all")
+	skipSecurityHeaders := flags.Bool("skip-security-headers", false, "don't check for security headers")
+	var requiredHeaders headerFlags
+	flags.Var(&requiredHeaders, "require-header", "header every response must have, can be repeated")
+	flags.Parse(args)
+	if flags.NArg() < 1 {
+		fmt.Println("⇨ usage: nano-web selftest [--paths file] [--sample n] [--require-header name] [--skip-security-headers] <url> [path...]")
+		os.Exit(2)
+	}
+	base := strings.TrimSuffix(flags.Arg(0), "/")
+	urlPaths := flags.Args()[1:]
+	if *pathsFile != "" {
+		dat, err := os.ReadFile(*pathsFile)
+		if err != nil {
+			fmt.Println("⇨ error:", err)
+			os.Exit(2)
+		}
+		for _, line := range strings.Split(string(dat), "\n") {
+			if line = strings.TrimSpace(line); line != "" {
+				urlPaths = append(urlPaths, line)
+			}
+		}
+	}
+	if len(urlPaths) == 0 && adminToken == "" {
+		fmt.Println("⇨ warning: set ADMIN_TOKEN to sample paths from the manifest, only checking /")
+	} else if len(urlPaths) == 0 {
+		sampled, err := selftestSample(base, adminToken, *sample)
+		if err != nil {
+			fmt.Println("⇨ error reading the manifest:", err)
+			os.Exit(2)
+		}
+		urlPaths = sampled
+	}
+	if len(urlPaths) == 0 {
+		urlPaths = []string{"/"}
+	}
+	failed := 0
+	for _, urlPath := range urlPaths {
+		problems := selftestPath(base, urlPath, requiredHeaders, !*skipSecurityHeaders)
+		if len(problems) == 0 {
+			fmt.Println("⇨ ok", urlPath)
+			continue
+		}
+		failed++
+		for _, problem := range problems {
+			fmt.Println("⇨ fail", urlPath+":", problem)
+		}
+	}
+	fmt.Println("⇨", len(urlPaths)-failed, "of", len(urlPaths), "paths passed")
+	if failed > 0 {
+		os.Exit(1)
+	}
+}
+
+type ExportedRoute struct {
+	File    string            `json:"file,omitempty"`
+	Status  int               `json:"status"`
+	Headers map[string]string `json:"headers"`
+}
+
+// The response headers a route is served with, besides those depending on the request
+func routeHeaders(route *Route) map[string]string {
+	headers := map[string]string{"Content-Type": route.ContentType, "Last-Modified": route.LastModified}
+	for name, value := range route.Headers {
+		headers[name] = value
+	}
+	return headers
+}
+
+// Write the exact bytes each route serves (templated, uncompressed) to a tar archive compressed
+// per its extension (.tar.zst, .tar.gz or .tar), with a manifest.json of statuses and headers by
+// url path, for `nano-web export --output site-snapshot.tar.zst`
+func exportSnapshot(args []string) {
+	flags := flag.NewFlagSet("export", flag.ExitOnError)
+	output := flags.String("output", "site-snapshot.tar.zst", "path of the snapshot to write")
+	flags.Parse(args)
+	checkConfigSchema()
+	checkPublicDir()
+	exported := make(Routes)
+	populateRoutes(exported, preflightRoutes())
+
+	files, routes, err := writeSnapshot(exported, *output)
+	if err != nil {
+		fmt.Println("⇨ error writing snapshot:", err)
+		os.Exit(-1)
+	}
+	fmt.Println("⇨ exported", files, "files and", routes, "routes to", *output)
+}
+
+// The name a route's body is stored under: its file's path within the public dir, or for
+// generated routes its hash, so no name is both a file and a directory
+func snapshotName(route *Route) string {
+	if route.Source != "" {
+		return "content" + getUrlPath(route.Source)
+	}
+	return "generated/" + route.Hash
+}
+
+// Write the routes to a tar, via a temporary file removed on failure, returning how many files
+// and routes were written
+func writeSnapshot(exported Routes, output string) (int, int, error) {
+	file, err := os.Create(output + ".tmp")
+	if err != nil {
+		return 0, 0, err
+	}
+	defer os.Remove(output + ".tmp")
+	defer file.Close()
+	var writer io.WriteCloser = file
+	switch {
+	case strings.HasSuffix(output, ".zst"):
+		writer, err = zstd.NewWriter(file)
+	case strings.HasSuffix(output, ".gz"), strings.HasSuffix(output, ".tgz"):
+		writer = gzip.NewWriter(file)
+	}
+	if err != nil {
+		return 0, 0, err
+	}
+	archive := tar.NewWriter(writer)
+	addFile := func(name string, dat []byte) error {
+		header := &tar.Header{Name: name, Mode: 0644, Size: int64(len(dat)), ModTime: time.Now()}
+		if err := archive.WriteHeader(header); err != nil {
+			return err
+		}
+		_, err := archive.Write(dat)
+		return err
+	}
+
+	manifest := make(map[string]ExportedRoute)
+	written := make(map[string]bool)
+	for _, urlPath := range sortedKeys(exported) {
+		route := exported[urlPath]
+		switch {
+		case route.Redirect != "":
+			manifest[urlPath] = ExportedRoute{Status: fasthttp.StatusMovedPermanently, Headers: map[string]string{"Location": route.Redirect}}
+			continue
+		case route.Status != 0:
+			manifest[urlPath] = ExportedRoute{Status: route.Status, Headers: map[string]string{}}
+			continue
+		case route.scheduledStatus(time.Now()) != 0:
+			manifest[urlPath] = ExportedRoute{Status: route.scheduledStatus(time.Now()), Headers: map[string]string{}}
+			continue
+		}
+		name := snapshotName(route)
+		if !written[name] {
+			var dat []byte
+			if route.FilePath != "" {
+				dat, err = os.ReadFile(route.FilePath)
+			} else {
+				var content Content
+				content, err = getContent(route)
+				dat = content.Plain
+			}
+			if err == nil {
+				err = addFile(name, dat)
+			}
+			if err != nil {
+				return 0, 0, fmt.Errorf("exporting %s: %w", urlPath, err)
+			}
+			written[name] = true
+		}
+		manifest[urlPath] = ExportedRoute{File: name, Status: fasthttp.StatusOK, Headers: routeHeaders(route)}
+	}
+	dat, _ := json.MarshalIndent(manifest, "", "  ")
+	err = addFile("manifest.json", dat)
+	if err == nil {
+		err = archive.Close()
+	}
+	if err == nil && writer != file {
+		err = writer.Close()
+	}
+	if err == nil {
+		err = file.Close()
+	}
+	if err == nil {
+		err = os.Rename(output+".tmp", output)
+	}
+	return len(written), len(manifest), err
+}
+
+// Read-only serving with --production (or PRODUCTION=1): content only changes on SIGHUP, with dev
+// mode refused, the reload endpoint limited to dry runs and nothing reindexing on its own
+var productionMode = slices.Contains(os.Args[1:], "--production") || getEnv("PRODUCTION", "0") == "1"
+
+func getDevMode() bool {
+	devMode := getEnv("DEV_MODE", "0") == "1"
+	if devMode && productionMode {
+		fmt.Println("⇨ DEV_MODE can't be used in production mode")
+		os.Exit(-1)
+	}
+	return devMode
+}
+
+var devMode = getDevMode()
+
+func printModeBanner() {
+	switch {
+	case productionMode:
+		fmt.Println("⇨ production mode: read-only, content only changes on SIGHUP")
+	case devMode:
+		fmt.Println("⇨ dev mode: watching the public directory for changes")
+	}
+}
+
+const liveReloadPath = "/_livereload"
+const liveReloadScriptPath = "/_nano-web/livereload.js"
+const liveReloadScript = `new EventSource("/_livereload").onmessage = function () {
+  location.reload();
+};
+`
+
+// Add the live reload script to HTML in dev mode, before </body> where there is one
+func injectLiveReload(dat []byte) []byte {
+	script := []byte(`<script src="` + liveReloadScriptPath + `"></script>`)
+	index := bytes.LastIndex(bytes.ToLower(dat), []byte("</body>"))
+	if index == -1 {
+		return append(dat, script...)
+	}
+	injected := make([]byte, 0, len(dat)+len(script))
+	injected = append(injected, dat[:index]...)
+	injected = append(injected, script...)
+	return append(injected, dat[index:]...)
+}
+
+// Browsers connected to the live reload event stream
+type LiveReload struct {
+	lock    sync.Mutex
+	clients map[chan struct{}]bool
+}
+
+var liveReload = LiveReload{clients: make(map[chan struct{}]bool)}
+
+func (liveReload *LiveReload) subscribe() chan struct{} {
+	client := make(chan struct{}, 1)
+	liveReload.lock.Lock()
+	defer liveReload.lock.Unlock()
+	liveReload.clients[client] = true
+	return client
+}
+
+func (liveReload *LiveReload) unsubscribe(client chan struct{}) {
+	liveReload.lock.Lock()
+	defer liveReload.lock.Unlock()
+	delete(liveReload.clients, client)
+}
+
+// Tell connected browsers to reload, if they haven't been told already
+func (liveReload *LiveReload) notify() {
+	if !devMode {
+		return
+	}
+	liveReload.lock.Lock()
+	defer liveReload.lock.Unlock()
+	for client := range liveReload.clients {
+		select {
+		case client <- struct{}{}:
+		default:
+		}
+	}
+}
+
+// Server-sent events telling the browser to reload, with periodic pings to notice closed connections
+func handleLiveReload(ctx *fasthttp.RequestCtx) {
+	ctx.SetContentType("text/event-stream")
+	ctx.Response.Header.Set("Cache-Control", "no-cache")
+	ctx.SetBodyStreamWriter(func(w *bufio.Writer) {
+		client := liveReload.subscribe()
+		defer liveReload.unsubscribe(client)
+		ping := time.NewTicker(15 * time.Second)
+		defer ping.Stop()
+		for {
+			select {
+			case <-client:
+				w.WriteString("data: reload\n\n")
+			case <-ping.C:
+				w.WriteString(": ping\n\n")
+			}
+			if err := w.Flush(); err != nil {
+				return
+			}
+		}
+	})
+}
+
+// Watch the public dirs for changes in dev mode, reindexing once changes have settled for an
+// interval. Falls back to polling where they can't be watched, e.g. on some network filesystems,
+// or with FOLLOW_SYMLINKS=always since symlinked directories outside them aren't watched.
+func watchRoutes(interval time.Duration) {
+	if followSymlinks == "always" {
+		pollRoutes(interval)
+		return
+	}
+	watcher, err := fsnotify.NewWatcher()
+	if err != nil {
+		fmt.Println("⇨ warning: can't watch the public directory, polling it instead:", err)
+		pollRoutes(interval)
+		return
+	}
+	defer watcher.Close()
+	for _, dir := range publicDirs {
+		if err := watchTree(watcher, dir); err != nil {
+			fmt.Println("⇨ warning: can't watch the public directory, polling it instead:", err)
+			watcher.Close()
+			pollRoutes(interval)
+			return
+		}
+	}
+	settle := time.NewTimer(interval)
+	settle.Stop()
+	for {
+		select {
+		case event, ok := <-watcher.Events:
+			if !ok {
+				return
+			}
+			if event.Has(fsnotify.Create) {
+				if info, err := os.Stat(event.Name); err == nil && info.IsDir() {
+					watchTree(watcher, event.Name)
+				}
+			}
+			if !settle.Stop() {
+				select {
+				case <-settle.C:
+				default:
+				}
+			}
+			settle.Reset(interval)
+		case err, ok := <-watcher.Errors:
+			if !ok {
+				return
+			}
+			fmt.Println("⇨ warning: error watching the public directory:", err)
+		case <-settle.C:
+			reindexRoutes(false)
+		}
+	}
+}
+
+// fsnotify doesn't watch recursively, so each directory under a root is watched, including ones
+// created later
+func watchTree(watcher *fsnotify.Watcher, root string) error {
+	return filepath.WalkDir(root, func(path string, entry fs.DirEntry, err error) error {
+		if err != nil {
+			return err
+		}
+		if entry.IsDir() {
+			return watcher.Add(path)
+		}
+		return nil
+	})
+}
+
+// Poll the public dir for changes, reindexing once changes have settled for an interval
+func pollRoutes(interval time.Duration) {
+	last := getContentFingerprint(collectFiles())
+	pending := false
+	for range time.Tick(interval) {
+		current := getContentFingerprint(collectFiles())
+		if current != last {
+			last = current
+			pending = true
+			continue
+		}
+		if pending {
+			pending = false
+			reindexRoutes(false)
+		}
+	}
+}
+
+var routeCachePath = getRouteCachePath()
+
+// With MAX_CACHE_MEMORY bodies live in the body cache rather than the route table, so there'd be
+// nothing to cache
+func getRouteCachePath() string {
+	path := getEnv("ROUTE_CACHE", "")
+	if path != "" && bodyCache != nil {
+		fmt.Println("⇨ ROUTE_CACHE can't be used with MAX_CACHE_MEMORY")
+		os.Exit(-1)
+	}
+	return path
+}
+var routeCacheFingerprint string
+
+// Settings which change how routes are built, and so invalidate the route cache
+var routeSettings = []string{
+	"ENCODINGS", "NO_COMPRESS", "BACKGROUND_RECOMPRESS", "LAZY_COMPRESS", "MAX_CACHE_FILE_SIZE", "COMPRESS_MIN_SIZE",
+	"COMPRESS_EXCLUDE", "TEMPLATE_MODE", "TEMPLATE_EXT", "DEV_MODE", "AUTOINDEX", "AUTOINDEX_EXCLUDE",
+	"CLEAN_URLS", "INDEX_FILES", "SERVE_HIDDEN", "FOLLOW_SYMLINKS", "EXCLUDE", "MAX_CACHE_MEMORY",
+}
+
+type cachedRoute struct {
+	UrlPaths     []string
+	ContentIndex int
+	Lazy         bool
+	Source       string
+	Size         int64
+	ModTime      int64
+	FilePath     string
+	Hash         string
+	ContentType  string
+	LastModified string
+	SourceHash   string
+	Headers      map[string]string
+	RedirectFrom []string
+	MetaModTime  int64
+	PublishAt    int64
+	UnpublishAt  int64
+	BuiltAt      int64
+}
+
+type routeCache struct {
+	Fingerprint string
+	// Bodies by content key, shared by the routes serving them
+	Contents []Content
+	Routes   []cachedRoute
+}
+
+// Bumped when routeCache's layout changes, so older caches are rebuilt rather than misread
+const routeCacheVersion = 2
+
+// Routes with the same content key serve identical bodies, so can share one copy of them
+func contentKey(route *Route) string {
+	return route.ContentType + "\x00" + route.Hash
+}
+
+// Identify the content and settings the routes are built from, by file size and mtime
+func getContentFingerprint(files []string) string {
+	hash := sha256.New()
+	for _, path := range files {
+		info, err := os.Stat(path)
+		if err != nil {
+			continue
+		}
+		fmt.Fprintf(hash, "%s\x00%d\x00%d\x00%d\n", path, info.Size(), info.ModTime().UnixNano(), metaModTime(path))
+	}
+	for _, name := range routeSettings {
+		fmt.Fprintf(hash, "%s=%s\n", name, os.Getenv(name))
+	}
+	fmt.Fprintf(hash, "version=%d\n", routeCacheVersion)
+	jsonString, _ := json.Marshal(config)
+	hash.Write(jsonString)
+	return hex.EncodeToString(hash.Sum(nil))
+}
+
+func loadRouteCache(routes Routes, path string, fingerprint string) bool {
+	file, err := os.Open(path)
+	if err != nil {
+		return false
+	}
+	defer file.Close()
+	reader, err := gzip.NewReader(file)
+	if err != nil {
+		return false
+	}
+	var cache routeCache
+	if err := gob.NewDecoder(reader).Decode(&cache); err != nil || cache.Fingerprint != fingerprint {
+		return false
+	}
+	lazies := make(map[int]*LazyContent)
+	for _, cached := range cache.Routes {
+		if cached.ContentIndex < 0 || cached.ContentIndex >= len(cache.Contents) {
+			return false
+		}
+	}
+	for _, cached := range cache.Routes {
+		route := &Route{
+			Content:      cache.Contents[cached.ContentIndex],
+			Source:       cached.Source,
+			Size:         cached.Size,
+			ModTime:      cached.ModTime,
+			FilePath:     cached.FilePath,
+			Hash:         cached.Hash,
+			ContentType:  cached.ContentType,
+			LastModified: cached.LastModified,
+			SourceHash:   cached.SourceHash,
+			Headers:      cached.Headers,
+			RedirectFrom: cached.RedirectFrom,
+			MetaModTime:  cached.MetaModTime,
+			PublishAt:    cached.PublishAt,
+			UnpublishAt:  cached.UnpublishAt,
+			BuiltAt:      cached.BuiltAt,
+		}
+		if cached.Lazy {
+			if lazies[cached.ContentIndex] == nil {
+				lazies[cached.ContentIndex] = &LazyContent{variants: make(map[string][]byte)}
+			}
+			route.Lazy = lazies[cached.ContentIndex]
+		}
+		for _, urlPath := range cached.UrlPaths {
+			routes[urlPath] = route
+		}
+	}
+	return true
+}
+
+// Write the route table to the cache, via a temporary file so a crash can't leave a partial cache
+func saveRouteCache(routes Routes, path string, fingerprint string) {
+	cache := routeCache{Fingerprint: fingerprint}
+	indexes := make(map[*Route]int)
+	contentIndexes := make(map[string]int)
+	routesLock.RLock()
+	for urlPath, route := range routes {
+		// Builtin routes are generated rather than cached
+		if route.Source == "" {
+			continue
+		}
+		i, exists := indexes[route]
+		if !exists {
+			i = len(cache.Routes)
+			indexes[route] = i
+			contentIndex, exists := contentIndexes[contentKey(route)]
+			if !exists {
+				contentIndex = len(cache.Contents)
+				contentIndexes[contentKey(route)] = contentIndex
+				cache.Contents = append(cache.Contents, route.Content)
+			}
+			cache.Routes = append(cache.Routes, cachedRoute{
+				ContentIndex: contentIndex,
+				Lazy:         route.Lazy != nil,
+				Source:       route.Source,
+				Size:         route.Size,
+				ModTime:      route.ModTime,
+				FilePath:     route.FilePath,
+				Hash:         route.Hash,
+				ContentType:  route.ContentType,
+				LastModified: route.LastModified,
+				SourceHash:   route.SourceHash,
+				Headers:      route.Headers,
+				RedirectFrom: route.RedirectFrom,
+				MetaModTime:  route.MetaModTime,
+				PublishAt:    route.PublishAt,
+				UnpublishAt:  route.UnpublishAt,
+				BuiltAt:      route.BuiltAt,
+			})
+		}
+		cache.Routes[i].UrlPaths = append(cache.Routes[i].UrlPaths, urlPath)
+	}
+	routesLock.RUnlock()
+
+	file, err := os.Create(path + ".tmp")
+	if err != nil {
+		fmt.Println("⇨ error writing route cache", err)
+		return
+	}
+	writer, _ := gzip.NewWriterLevel(file, gzip.BestSpeed)
+	err = gob.NewEncoder(writer).Encode(cache)
+	if err == nil {
+		err = writer.Close()
+	}
+	file.Close()
+	if err == nil {
+		err = os.Rename(path+".tmp", path)
+	}
+	if err != nil {
+		fmt.Println("⇨ error writing route cache", err)
+		os.Remove(path + ".tmp")
+	}
+}
+
+// Recompress all compressed routes at the best levels and swap them in once done
+func recompressRoutes() {
+	routesLock.RLock()
+	// Routes with identical bodies are recompressed once, so they keep sharing the result
+	compressed := make(map[string][]*Route)
+	seen := make(map[*Route]bool)
+	for _, route := range routes {
+		if !seen[route] && (route.Content.Gzip != nil || route.Content.Brotli != nil) {
+			seen[route] = true
+			compressed[contentKey(route)] = append(compressed[contentKey(route)], route)
+		}
+	}
+	routesLock.RUnlock()
+
+	recompressed := make(map[*Route]*Route, len(seen))
+	var recompressedLock sync.Mutex
+	var wg sync.WaitGroup
+	queue := make(chan []*Route)
+	for i := 0; i < runtime.NumCPU(); i++ {
+		wg.Add(1)
+		go func() {
+			defer wg.Done()
+			for group := range queue {
+				content := compressContent(group[0].Content.Plain, encodings, bestLevels)
+				recompressedLock.Lock()
+				for _, route := range group {
+					newRoute := *route
+					newRoute.Content = content
+					recompressed[route] = &newRoute
+				}
+				recompressedLock.Unlock()
+			}
+		}()
+	}
+	for _, group := range compressed {
+		queue <- group
+	}
+	close(queue)
+	wg.Wait()
+
+	routesLock.Lock()
+	for urlPath, route := range routes {
+		if newRoute, exists := recompressed[route]; exists {
+			routes[urlPath] = newRoute
+		}
+	}
+	routesLock.Unlock()
+	fmt.Println("⇨ recompressed", len(recompressed), "routes at best levels")
+	if routeCachePath != "" {
+		saveRouteCache(routes, routeCachePath, routeCacheFingerprint)
+	}
+}
+
+func getRoute(urlPath string) (*Route, bool) {
+	routesLock.RLock()
+	defer routesLock.RUnlock()
+	route, exists := routes[urlPath]
+	return route, exists
+}
+
+func acceptsEncoding(ctx *fasthttp.RequestCtx, encoding string) bool {
+	return strings.Contains(string(ctx.Request.Header.Peek("Accept-Encoding")), encoding)
+}
+
+// The route's body, rebuilt from disk if it was evicted from the body cache
+func getContent(route *Route) (Content, error) {
+	if bodyCache == nil || route.Source == "" {
+		return route.Content, nil
+	}
+	if content, exists := bodyCache.Get(route.Source); exists {
+		return content, nil
+	}
+	// Concurrent misses for an evicted body, e.g. a CDN's shield and edge nodes all re-requesting
+	// it at once, share one rebuild
+	rebuilt, err := bodyBuilds.Do(route.Source, func() (*Route, error) {
+		rebuilt, err := makeRoute(route.Source)
+		if err != nil {
+			return nil, err
+		}
+		bodyCache.Put(route.Source, rebuilt.Content)
+		return rebuilt, nil
+	})
+	if err != nil {
+		return Content{}, err
+	}
+	return rebuilt.Content, nil
+}
+
+// Pick the best compressed variant the client accepts, falling back to plain
+func getEncodedContent(ctx *fasthttp.RequestCtx, route *Route, content Content) (string, []byte) {
+	if route.Lazy != nil {
+		for _, encoding := range []string{"br", "gzip"} {
+			if !encodings[encoding] || !acceptsEncoding(ctx, encoding) {
+				continue
+			}
+			if bodyCache != nil && route.Source != "" {
+				return encoding, getCachedVariant(route, content, encoding)
+			}
+			return encoding, route.Lazy.Get(encoding, content.Plain)
+		}
+		return "", content.Plain
+	}
+	if content.Brotli != nil && acceptsEncoding(ctx, "br") {
+		return "br", content.Brotli
+	}
+	if content.Gzip != nil && acceptsEncoding(ctx, "gzip") {
+		return "gzip", content.Gzip
+	}
+	return "", content.Plain
+}
+
+type fileSection struct {
+	io.Reader
+	file *os.File
+}
+
+// fasthttp closes body streams implementing io.Closer once they're written
+func (section *fileSection) Close() error {
+	return section.file.Close()
+}
+
+// Stream a file from disk, with support for single byte ranges
+func serveFile(ctx *fasthttp.RequestCtx, path string) {
+	file, err := os.Open(path)
+	if err != nil {
+		serveError(ctx, fasthttp.StatusNotFound)
+		return
+	}
+	info, err := file.Stat()
+	if err != nil {
+		file.Close()
+		serveError(ctx, fasthttp.StatusInternalServerError)
+		return
+	}
+	size := int(info.Size())
+	ctx.Response.Header.Set("Accept-Ranges", "bytes")
+
+	rangeHeader := ctx.Request.Header.Peek("Range")
+	if len(rangeHeader) == 0 {
+		ctx.SetBodyStream(file, size)
+		return
+	}
+	start, end, err := fasthttp.ParseByteRange(rangeHeader, size)
+	if err != nil {
+		file.Close()
+		ctx.Response.Header.Set("Content-Range", fmt.Sprintf("bytes */%d", size))
+		ctx.SetStatusCode(fasthttp.StatusRequestedRangeNotSatisfiable)
+		return
+	}
+	ctx.Response.Header.SetContentRange(start, end, size)
+	ctx.SetStatusCode(fasthttp.StatusPartialContent)
+	ctx.SetBodyStream(&fileSection{io.NewSectionReader(file, int64(start), int64(end-start+1)), file}, end-start+1)
+}
+
+// Resolve a url path to a file in the first public dir that has it, including directory index
+// files and with CLEAN_URLS extensionless HTML files
+func resolveFile(urlPath string) (string, bool) {
+	if isHidden(urlPath) || isExcluded(urlPath) {
+		return "", false
+	}
+	for _, publicDir := range publicDirs {
+		// Sidecars configure the file beside them and are never served themselves
+		if path, exists := resolvePublicFile(publicDir, urlPath); exists && !strings.HasSuffix(path, metaSuffix) {
+			return path, true
+		}
+	}
+	return "", false
+}
+
+func resolvePublicFile(publicDir string, urlPath string) (string, bool) {
+	path := filepath.Join(publicDir, filepath.FromSlash(filepath.Clean("/"+urlPath)))
+	info, err := os.Stat(path)
+	if err == nil && info.IsDir() {
+		dir := path
+		for _, name := range indexFiles {
+			path = filepath.Join(dir, name)
+			if info, err = os.Stat(path); err == nil {
+				break
+			}
+		}
+	} else if err != nil && cleanUrls {
+		path += ".html"
+		info, err = os.Stat(path)
+	}
+	if err != nil || info.IsDir() || !symlinkAllowed(path) {
+		return "", false
+	}
+	return path, true
+}
+
+// Swap a route for its replacement under every url path it's served from, or remove it
+func replaceRoute(old *Route, replacement *Route) {
+	routesLock.Lock()
+	defer routesLock.Unlock()
+	for urlPath, route := range routes {
+		if route != old {
+			continue
+		}
+		if replacement == nil {
+			delete(routes, urlPath)
+		} else {
+			routes[urlPath] = replacement
+		}
+	}
+}
+
+type flight struct {
+	done  sync.WaitGroup
+	route *Route
+	err   error
+}
+
+// Runs one build per key at a time, with concurrent callers for the same key waiting for its result
+type SingleFlight struct {
+	lock    sync.Mutex
+	flights map[string]*flight
+}
+
+var routeBuilds = SingleFlight{flights: make(map[string]*flight)}
+var bodyBuilds = SingleFlight{flights: make(map[string]*flight)}
+
+func (s *SingleFlight) Do(key string, build func() (*Route, error)) (*Route, error) {
+	s.lock.Lock()
+	if f, exists := s.flights[key]; exists {
+		s.lock.Unlock()
+		f.done.Wait()
+		return f.route, f.err
+	}
+	f := &flight{}
+	f.done.Add(1)
+	s.flights[key] = f
+	s.lock.Unlock()
+
+	f.route, f.err = build()
+	s.lock.Lock()
+	delete(s.flights, key)
+	s.lock.Unlock()
+	f.done.Done()
+	return f.route, f.err
+}
+
+// How requests check the disk in dev mode: "stat" on every request, "interval:200ms" at most
+// once per interval per file, or "watch" never, relying on the background watcher
+func getDevRefresh() (string, time.Duration) {
+	refresh := getEnv("DEV_REFRESH", "stat")
+	if refresh == "stat" || refresh == "watch" {
+		return refresh, 0
+	}
+	if strings.HasPrefix(refresh, "interval:") {
+		interval, err := time.ParseDuration(strings.TrimPrefix(refresh, "interval:"))
+		if err == nil && interval > 0 {
+			return "interval", interval
+		}
+	}
+	fmt.Println("⇨ warning: unknown DEV_REFRESH", refresh+", using stat")
+	return "stat", 0
+}
+
+var devRefresh, devRefreshInterval = getDevRefresh()
+var refreshChecksLock sync.Mutex
+var refreshChecks = make(map[string]time.Time)
+
+// Whether a request should check the disk for this path, per DEV_REFRESH
+func shouldCheckDisk(path string) bool {
+	switch devRefresh {
+	case "watch":
+		return false
+	case "interval":
+		now := time.Now()
+		refreshChecksLock.Lock()
+		defer refreshChecksLock.Unlock()
+		if now.Sub(refreshChecks[path]) < devRefreshInterval {
+			return false
+		}
+		refreshChecks[path] = now
+		return true
+	default:
+		return true
+	}
+}
+
+// Whether the disk check for this path was skipped by a request that started at a time
+func refreshSkipped(path string, since time.Time) bool {
+	switch devRefresh {
+	case "watch":
+		return true
+	case "interval":
+		refreshChecksLock.Lock()
+		defer refreshChecksLock.Unlock()
+		return refreshChecks[path].Before(since)
+	default:
+		return false
+	}
+}
+
+// In dev mode, check a route's file before serving it, dropping the route if the file
+// was deleted and rebuilding it if it was modified since
+func refreshRoute(route *Route) (*Route, bool) {
+	if route.Source == "" || !shouldCheckDisk(route.Source) {
+		return route, true
+	}
+	info, err := os.Stat(route.Source)
+	// A vanished mount looks like every file was deleted
+	if errors.Is(err, os.ErrNotExist) && !degraded.Load() && publicDirsAvailable() {
+		fmt.Println("⇨ removing route for deleted", route.Source)
+		replaceRoute(route, nil)
+		liveReload.notify()
+		return nil, false
+	}
+	if err != nil || (info.Size() == route.Size && info.ModTime().UnixNano() == route.ModTime &&
+		metaModTime(route.Source) == route.MetaModTime) {
+		return route, true
+	}
+	// Concurrent requests for a modified file share one rebuild
+	rebuilt, err := routeBuilds.Do(route.Source, func() (*Route, error) {
+		rebuilt, err := makeRoute(route.Source)
+		if err != nil {
+			return nil, err
+		}
+		fmt.Println("⇨ refreshing route for", route.Source)
+		cacheBody(rebuilt)
+		replaceRoute(route, rebuilt)
+		liveReload.notify()
+		return rebuilt, nil
+	})
+	if err != nil {
+		fmt.Println("⇨ error refreshing route for", route.Source+":", err)
+		return route, true
+	}
+	return rebuilt, true
+}
+
+// Look up a route, checking the disk for it while routes are still being populated, or
+// in dev mode for files created since the last reindex
+func lookupRoute(urlPath string) (*Route, bool) {
+	route, exists := getRoute(urlPath)
+	if exists && devMode {
+		return refreshRoute(route)
+	}
+	isPopulated := populated.Load()
+	if exists || (isPopulated && (!devMode || !shouldCheckDisk(urlPath))) {
+		return route, exists
+	}
+	path, exists := resolveFile(urlPath)
+	if !exists {
+		return nil, false
+	}
+	route, err := routeBuilds.Do(path, func() (*Route, error) {
+		route, err := makeRoute(path)
+		if err != nil || !isPopulated {
+			return route, err
+		}
+		fmt.Println("⇨ adding route", getUrlPath(path), "→", path)
+		cacheBody(route)
+		routesLock.Lock()
+		addRoute(routes, getUrlPath(path), path, route)
+		routesLock.Unlock()
+		liveReload.notify()
+		return route, nil
+	})
+	if err != nil {
+		return nil, false
+	}
+	return route, true
+}
+
+// A step in resolving a request path to a route, tried in RESOLVE_ORDER until one matches
+type ResolveStage func(urlPath string) (*Route, bool)
+
+var resolveStages = map[string]ResolveStage{
+	"file":    lookupRoute,
+	"archive": resolveArchive,
+	"origin":  resolveOrigin,
+	"spa":     resolveSpa,
+}
+
+var serveArchives = getEnv("SERVE_ARCHIVES", "0") == "1"
+
+type openArchive struct {
+	key     string
+	reader  *zip.ReadCloser
+	entries map[string]*zip.File
+	// Reads in progress, so an evicted archive is only closed once they're done
+	refs    int
+	evicted bool
+}
+
+// An LRU of open zip archives, so entries can be read without reopening them on each request
+type ArchiveCache struct {
+	lock     sync.Mutex
+	capacity int
+	entries  map[string]*list.Element
+	order    *list.List
+}
+
+var archiveCache = ArchiveCache{
+	capacity: getEnvInt("ARCHIVE_CACHE_SIZE", 16),
+	entries:  make(map[string]*list.Element),
+	order:    list.New(),
+}
+
+// The most read into memory from an archive entry or the origin: MAX_CACHE_FILE_SIZE, or 64MiB
+// without it, so a small archive or a misbehaving origin can't take more than a file would
+func getBufferLimit() int {
+	if maxCacheFileSize > 0 {
+		return maxCacheFileSize
+	}
+	return 64 * 1024 * 1024
+}
+
+var bufferLimit = getBufferLimit()
+
+// Entry bodies read from archives, compressed, by archive and entry name
+var archiveEntries = newBodyCache(getEnvSize("ARCHIVE_CACHE_MEMORY", 64*1024*1024))
+
+var errArchiveEntryTooLarge = errors.New("archive entry too large")
+
+// Open an archive, or reuse it if it's open, keyed by its route so a replaced archive is
+// reopened. It stays open until released, even if evicted in the meantime.
+func (cache *ArchiveCache) acquire(route *Route) (*openArchive, error) {
+	cache.lock.Lock()
+	defer cache.lock.Unlock()
+	key := fmt.Sprintf("%s\x00%d", route.Source, route.ModTime)
+	element, exists := cache.entries[key]
+	if exists {
+		cache.order.MoveToFront(element)
+	} else {
+		reader, err := zip.OpenReader(route.Source)
+		if err != nil {
+			return nil, err
+		}
+		archive := &openArchive{key: key, reader: reader, entries: make(map[string]*zip.File)}
+		for _, file := range reader.File {
+			archive.entries[strings.TrimPrefix(file.Name, "/")] = file
+		}
+		element = cache.order.PushFront(archive)
+		cache.entries[key] = element
+		for cache.order.Len() > max(cache.capacity, 1) {
+			oldest := cache.order.Back()
+			cache.order.Remove(oldest)
+			evicted := oldest.Value.(*openArchive)
+			delete(cache.entries, evicted.key)
+			evicted.evicted = true
+			if evicted.refs == 0 {
+				evicted.reader.Close()
+			}
+		}
+	}
+	archive := element.Value.(*openArchive)
+	archive.refs++
+	return archive, nil
+}
+
+func (cache *ArchiveCache) release(archive *openArchive) {
+	cache.lock.Lock()
+	defer cache.lock.Unlock()
+	archive.refs--
+	if archive.evicted && archive.refs == 0 {
+		archive.reader.Close()
+	}
+}
+
+// An entry by name, or a directory's index file
+func (archive *openArchive) find(name string) (*zip.File, bool) {
+	file, exists := archive.entries[name]
+	if exists && !file.FileInfo().IsDir() {
+		return file, true
+	}
+	dir := strings.TrimSuffix(name, "/")
+	if dir != "" {
+		dir += "/"
+	}
+	for _, indexFile := range indexFiles {
+		if file, exists = archive.entries[dir+indexFile]; exists {
+			return file, true
+		}
+	}
+	return nil, false
+}
+
+// Read an entry from an archive, from the entry cache when it's been read before. Archives are
+// only locked while being opened, so slow reads don't hold up other requests.
+func (cache *ArchiveCache) Read(route *Route, name string) (Content, *zip.File, error) {
+	archive, err := cache.acquire(route)
+	if err != nil {
+		return Content{}, nil, err
+	}
+	defer cache.release(archive)
+	file, exists := archive.find(name)
+	if !exists {
+		return Content{}, nil, os.ErrNotExist
+	}
+	key := archive.key + "\x00" + file.Name
+	if content, exists := archiveEntries.Get(key); exists {
+		return content, file, nil
+	}
+	if file.UncompressedSize64 > uint64(bufferLimit) {
+		return Content{}, nil, errArchiveEntryTooLarge
+	}
+	reader, err := file.Open()
+	if err != nil {
+		return Content{}, nil, err
+	}
+	defer reader.Close()
+	// The header's size can't be trusted, so stop reading past the limit too
+	dat, err := io.ReadAll(io.LimitReader(reader, int64(bufferLimit)+1))
+	if err != nil {
+		return Content{}, nil, err
+	}
+	if len(dat) > bufferLimit {
+		return Content{}, nil, errArchiveEntryTooLarge
+	}
+	content := Content{Plain: dat}
+	mimetype := getMimetype(strings.ToLower(filepath.Ext(file.Name)))
+	if compressedType(mimetype) && len(dat) >= compressMinSize {
+		content = compressContent(dat, encodings, defaultLevels)
+	}
+	archiveEntries.Put(key, content)
+	return content, file, nil
+}
+
+// The archive route a path like /releases/v1.2.3/guide.html is read from and the entry's name
+// within it, trying the longest archive path first
+func findArchive(urlPath string) (*Route, string, bool) {
+	if !serveArchives {
+		return nil, "", false
+	}
+	segments := strings.Split(strings.TrimPrefix(urlPath, "/"), "/")
+	for i := len(segments) - 1; i >= 1; i-- {
+		archive, exists := getRoute("/" + strings.Join(segments[:i], "/") + ".zip")
+		if exists && archive.Source != "" {
+			return archive, strings.Join(segments[i:], "/"), true
+		}
+	}
+	return nil, "", false
+}
+
+// Serve /releases/v1.2.3/guide.html from guide.html inside /releases/v1.2.3.zip
+func resolveArchive(urlPath string) (*Route, bool) {
+	// Entries are hidden and excluded by their url path like files, e.g. /releases/v1.2.3/.env
+	if isHidden(urlPath) || isExcluded(urlPath) {
+		return nil, false
+	}
+	archive, name, exists := findArchive(urlPath)
+	if !exists {
+		return nil, false
+	}
+	content, file, err := archiveCache.Read(archive, name)
+	if err != nil {
+		if errors.Is(err, errArchiveEntryTooLarge) {
+			fmt.Println("⇨ warning: not serving", urlPath+",", "larger than", bufferLimit, "bytes uncompressed")
+		}
+		return nil, false
+	}
+	return &Route{
+		Content:      content,
+		Hash:         fmt.Sprintf("%x-%x", file.CRC32, file.UncompressedSize64),
+		ContentType:  getMimetype(strings.ToLower(filepath.Ext(file.Name))),
+		LastModified: file.Modified.UTC().Format(http.TimeFormat),
+	}, true
+}
+
+// Origin that paths without a file are fetched from and cached, e.g. https://upstream.example.com
+var originUrl = strings.TrimSuffix(getEnv("ORIGIN_URL", ""), "/")
+var originTtl = time.Duration(getEnvInt("ORIGIN_TTL", 300)) * time.Second
+
+var originClient = &http.Client{Timeout: 10 * time.Second}
+
+type originEntry struct {
+	// nil for paths the origin doesn't have, otherwise the route without its body
+	route   *Route
+	expires time.Time
+}
+
+// Responses fetched from ORIGIN_URL by url path, until their TTL passes. Bodies are held in a
+// BodyCache within ORIGIN_CACHE_MEMORY, and at most ORIGIN_CACHE_ENTRIES paths are remembered,
+// hits and misses alike, so a crawl of unknown paths can't grow it without bound.
+type OriginCache struct {
+	lock     sync.RWMutex
+	capacity int
+	entries  map[string]originEntry
+	bodies   *BodyCache
+}
+
+var originCache = OriginCache{
+	capacity: max(getEnvInt("ORIGIN_CACHE_ENTRIES", 10000), 1),
+	entries:  make(map[string]originEntry),
+	bodies:   newBodyCache(getEnvSize("ORIGIN_CACHE_MEMORY", 64*1024*1024)),
+}
+var originFetches = SingleFlight{flights: make(map[string]*flight)}
+
+// A cached route, or nil for a cached miss. Entries whose body was evicted are misses.
+func (cache *OriginCache) Get(urlPath string) (*Route, bool) {
+	cache.lock.RLock()
+	entry, exists := cache.entries[urlPath]
+	cache.lock.RUnlock()
+	if !exists || !time.Now().Before(entry.expires) {
+		return nil, false
+	}
+	if entry.route == nil {
+		return nil, true
+	}
+	content, exists := cache.bodies.Get(urlPath)
+	if !exists {
+		return nil, false
+	}
+	route := *entry.route
+	route.Content = content
+	return &route, true
+}
+
+func (cache *OriginCache) Set(urlPath string, route *Route) {
+	var entry originEntry
+	if route != nil {
+		cache.bodies.Put(urlPath, route.Content)
+		stripped := *route
+		stripped.Content = Content{}
+		entry.route = &stripped
+	}
+	entry.expires = time.Now().Add(originTtl)
+	cache.lock.Lock()
+	defer cache.lock.Unlock()
+	if _, exists := cache.entries[urlPath]; !exists && len(cache.entries) >= cache.capacity {
+		// Make room by dropping an arbitrary entry, which map iteration order gives us
+		for evicted := range cache.entries {
+			delete(cache.entries, evicted)
+			break
+		}
+	}
+	cache.entries[urlPath] = entry
+}
+
+// Drop expired entries every TTL, so paths that aren't requested again don't hold memory
+func (cache *OriginCache) sweep() {
+	for range time.Tick(originTtl) {
+		now := time.Now()
+		cache.lock.Lock()
+		for urlPath, entry := range cache.entries {
+			if now.After(entry.expires) {
+				delete(cache.entries, urlPath)
+			}
+		}
+		cache.lock.Unlock()
+	}
+}
+
+// Fetch a path from the origin into a route, or nil if the origin doesn't have it
+func fetchOrigin(urlPath string) (*Route, error) {
+	response, err := originClient.Get(originUrl + (&url.URL{Path: urlPath}).EscapedPath())
+	if err != nil {
+		return nil, err
+	}
+	defer response.Body.Close()
+	if response.StatusCode == http.StatusNotFound || response.StatusCode == http.StatusGone {
+		return nil, nil
+	}
+	if response.StatusCode != http.StatusOK {
+		return nil, fmt.Errorf("origin responded %s", response.Status)
+	}
+	dat, err := io.ReadAll(io.LimitReader(response.Body, int64(bufferLimit)+1))
+	if err != nil {
+		return nil, err
+	}
+	if len(dat) > bufferLimit {
+		return nil, fmt.Errorf("origin response larger than %d bytes", bufferLimit)
+	}
+	mimetype, _, _ := strings.Cut(response.Header.Get("Content-Type"), ";")
+	mimetype = strings.TrimSpace(mimetype)
+	if mimetype == "" {
+		mimetype = getMimetype(strings.ToLower(path.Ext(urlPath)))
+	}
+	lastModified := response.Header.Get("Last-Modified")
+	if lastModified == "" {
+		lastModified = time.Now().UTC().Format(http.TimeFormat)
+	}
+	content := Content{Plain: dat}
+	if compressedType(mimetype) && len(dat) >= compressMinSize && !compressExclude.Match(urlPath) {
+		content = compressContent(dat, encodings, getStartupLevels())
+	}
+	return &Route{
+		Content:      content,
+		Size:         int64(len(dat)),
+		Hash:         fmt.Sprintf("%x", sha256.Sum256(dat)),
+		ContentType:  mimetype,
+		LastModified: lastModified,
+	}, nil
+}
+
+// Serve paths without a file from the origin, fetching each once per ORIGIN_TTL. Misses are
+// cached too, so unknown paths don't hammer the origin, but failed fetches aren't.
+func resolveOrigin(urlPath string) (*Route, bool) {
+	if originUrl == "" || isHidden(urlPath) || isExcluded(urlPath) {
+		return nil, false
+	}
+	if route, exists := originCache.Get(urlPath); exists {
+		return route, route != nil
+	}
+	route, err := originFetches.Do(urlPath, func() (*Route, error) {
+		route, err := fetchOrigin(urlPath)
+		if err == nil {
+			originCache.Set(urlPath, route)
+		}
+		return route, err
+	})
+	if err != nil {
+		fmt.Println("⇨ error fetching", urlPath, "from origin:", err)
+		return nil, false
+	}
+	return route, route != nil
+}
+
+// An SPA served under a url prefix, with the document 404s under it fall back to for client
+// side routing
+type SpaMount struct {
+	Prefix   string
+	Fallback string
+}
+
+// Comma separated SPA_MOUNTS of url prefixes and their fallback documents, e.g.
+// "/admin=/admin/index.html,/shop=/shop/index.html", longest prefix first, with SPA_MODE=1
+// mounting "/" last
+func getSpaMounts() []SpaMount {
+	var mounts []SpaMount
+	for _, mapping := range strings.Split(getEnv("SPA_MOUNTS", ""), ",") {
+		if strings.TrimSpace(mapping) == "" {
+			continue
+		}
+		prefix, fallback, found := strings.Cut(strings.TrimSpace(mapping), "=")
+		if !found || !strings.HasPrefix(prefix, "/") || !strings.HasPrefix(fallback, "/") {
+			fmt.Println("⇨ invalid mapping in SPA_MOUNTS:", mapping)
+			os.Exit(-1)
+		}
+		mounts = append(mounts, SpaMount{strings.TrimSuffix(prefix, "/"), fallback})
+	}
+	sort.SliceStable(mounts, func(i, j int) bool { return len(mounts[i].Prefix) > len(mounts[j].Prefix) })
+	if getEnv("SPA_MODE", "0") == "1" {
+		mounts = append(mounts, SpaMount{"", "/"})
+	}
+	return mounts
+}
+
+var spaMounts = getSpaMounts()
+
+// The status the SPA fallback is served with, e.g. 404 so crawlers don't index unknown paths
+var spaStatus = getEnvInt("SPA_STATUS", fasthttp.StatusOK)
+
+// Whether a resolved route is the SPA fallback for a path without a file of its own
+func isSpaFallback(urlPath string, route *Route) bool {
+	if _, exists := getRoute(urlPath); exists {
+		return false
+	}
+	fallback, exists := resolveSpa(urlPath)
+	return exists && fallback == route
+}
+
+// Paths that 404 rather than fall back to the SPA, by default those with an extension, so a
+// missing asset shows up as a broken build instead of an HTML page
+var spaExclude = getGlobs("SPA_EXCLUDE", "*.*")
+
+// Serve the fallback document of the SPA mounted at the longest prefix of the path
+func resolveSpa(urlPath string) (*Route, bool) {
+	if spaExclude.Match(urlPath) {
+		return nil, false
+	}
+	for _, mount := range spaMounts {
+		if urlPath == mount.Prefix || strings.HasPrefix(urlPath, mount.Prefix+"/") {
+			return lookupRoute(mount.Fallback)
+		}
+	}
+	return nil, false
+}
+
+func getResolveOrder() []ResolveStage {
+	var order []ResolveStage
+	for _, name := range strings.Split(getEnv("RESOLVE_ORDER", "file,archive,origin,spa"), ",") {
+		name = strings.TrimSpace(name)
+		if stage, exists := resolveStages[name]; exists {
+			order = append(order, stage)
+		} else if name != "" {
+			fmt.Println("⇨ warning: unknown RESOLVE_ORDER stage", name)
+		}
+	}
+	return order
+}
+
+var resolveOrder = getResolveOrder()
+
+func resolveRoute(urlPath string) (*Route, bool) {
+	for _, stage := range resolveOrder {
+		if route, exists := stage(urlPath); exists {
+			return route, true
+		}
+	}
+	return nil, false
+}
+
+// W3C trace context, version 00: 00-<trace id>-<parent id>-<flags>
+var traceparentPattern = regexp.MustCompile(`^00-([0-9a-f]{32})-([0-9a-f]{16})-([0-9a-f]{2})$`)
+var traceSampleRate = getEnvFloat("TRACE_SAMPLE_RATE", 1)
+
+// Echo valid trace context headers back, and return the trace id if this request should be logged with it
+func getTraceId(ctx *fasthttp.RequestCtx) string {
+	traceparent := string(ctx.Request.Header.Peek("traceparent"))
+	match := traceparentPattern.FindStringSubmatch(traceparent)
+	if match == nil || match[1] == strings.Repeat("0", 32) || match[2] == strings.Repeat("0", 16) {
+		return ""
+	}
+	ctx.Response.Header.Set("traceparent", traceparent)
+	if tracestate := ctx.Request.Header.Peek("tracestate"); len(tracestate) > 0 {
+		ctx.Response.Header.SetBytesV("tracestate", tracestate)
+	}
+	flags, _ := strconv.ParseUint(match[3], 16, 8)
+	if flags&1 == 0 || rand.Float64() >= traceSampleRate {
+		return ""
+	}
+	return match[1]
+}
+
+func handler(ctx *fasthttp.RequestCtx) {
+	logLine := []any{"⇨ request", string(ctx.Path())}
+	if len(requestClasses) > 0 {
+		logLine = append(logLine, "class="+classifyPath(string(ctx.Path())))
+	}
+	if traceId := getTraceId(ctx); traceId != "" {
+		logLine = append(logLine, "trace="+traceId)
+		ctx.SetUserValue("traceId", traceId)
+	}
+	fmt.Println(logLine...)
+	if metricsPath != "" && string(ctx.Path()) == metricsPath {
+		writeMetrics(ctx)
+		return
+	}
+	if devMode && string(ctx.Path()) == liveReloadPath {
+		handleLiveReload(ctx)
+		return
+	}
+	if string(ctx.Path()) == diffPath && isAdmin(ctx) {
+		handleDiff(ctx)
+		return
+	}
+	if string(ctx.Path()) == verifyPath && isAdmin(ctx) {
+		handleVerify(ctx)
+		return
+	}
+	if string(ctx.Path()) == manifestPath && isAdmin(ctx) {
+		handleManifest(ctx)
+		return
+	}
+	if string(ctx.Path()) == reloadPath && isAdmin(ctx) {
+		handleReloadRequest(ctx)
+		return
+	}
+	if string(ctx.Path()) == readyPath {
+		handleReady(ctx)
+		return
+	}
+	if oidcIssuer != "" && string(ctx.Path()) == oidcCallbackPath {
+		handleOidcCallback(ctx)
+		return
+	}
+	if len(gitRefs) > 0 && strings.HasPrefix(string(ctx.Path()), gitVersionPrefix) {
+		handleGitVersion(ctx)
+		return
+	}
+	if !populated.Load() && !lazyPopulate {
+		ctx.Response.Header.Set("Retry-After", "1")
+		ctx.Error("Service Unavailable", fasthttp.StatusServiceUnavailable)
+		return
+	}
+	if cleanUrls && strings.HasSuffix(string(ctx.Path()), ".html") {
+		if _, exists := getRoute(string(ctx.Path())); exists {
+			target := cleanUrl(string(ctx.Path()))
+			if query := ctx.QueryArgs().String(); query != "" {
+				target += "?" + query
+			}
+			ctx.Redirect(target, fasthttp.StatusMovedPermanently)
+			return
+		}
+	}
+	if target, redirect := trailingSlashRedirect(string(ctx.Path())); redirect {
+		if query := ctx.QueryArgs().String(); query != "" {
+			target += "?" + query
+		}
+		ctx.Redirect(target, fasthttp.StatusMovedPermanently)
+		return
+	}
+	route, exists := resolveRoute(string(ctx.Path()))
+	if !exists {
+		serveError(ctx, fasthttp.StatusNotFound)
+		return
+	}
+	if route.JsonIndex != nil {
+		addVary(ctx, "Accept")
+		if string(ctx.QueryArgs().Peek("format")) == "json" ||
+			bytes.Contains(ctx.Request.Header.Peek("Accept"), []byte("application/json")) {
+			route = route.JsonIndex
+		}
+	}
+
+	if route.Redirect != "" {
+		ctx.Redirect(route.Redirect, fasthttp.StatusMovedPermanently)
+		return
+	}
+	if route.Status != 0 {
+		serveError(ctx, route.Status)
+		return
+	}
+	if status := route.scheduledStatus(time.Now()); status != 0 {
+		serveError(ctx, status)
+		return
+	}
+
+	ctx.Response.Header.Set("Content-Type", route.ContentType)
+	ctx.Response.Header.Set("Server", "nano-web")
+	ctx.Response.Header.Set("Last-Modified", route.LastModified)
+	for name, value := range route.Headers {
+		ctx.Response.Header.Set(name, value)
+	}
+	// So developers can tell whether they're looking at stale output
+	if devMode && route.BuiltAt != 0 {
+		refresh := "checked"
+		if refreshSkipped(route.Source, ctx.Time()) {
+			refresh = "skipped"
+		}
+		age := time.Since(time.Unix(0, route.BuiltAt)).Round(time.Millisecond)
+		ctx.Response.Header.Set("X-Nano-Cache-Age", age.String()+"; refresh="+refresh)
+	}
+	// Clients with an up to date copy get a 304, keeping the headers but not the body
+	if lastModified, err := fasthttp.ParseHTTPDate([]byte(route.LastModified)); err == nil && !ctx.IfModifiedSince(lastModified) {
+		ctx.SetStatusCode(fasthttp.StatusNotModified)
+		return
+	}
+	if route.FilePath != "" {
+		serveFile(ctx, route.FilePath)
+		return
+	}
+	routeContent, err := getContent(route)
+	if err != nil {
+		fmt.Println("⇨ error rebuilding route", string(ctx.Path())+":", err)
+		serveError(ctx, fasthttp.StatusInternalServerError)
+		return
+	}
+	writeContent(ctx, route, routeContent)
+	if spaStatus != fasthttp.StatusOK && ctx.Response.StatusCode() == fasthttp.StatusOK && isSpaFallback(string(ctx.Path()), route) {
+		ctx.SetStatusCode(spaStatus)
+	}
+}
+
+// Serve a percentage of compressed responses with an alternate encoding and level, labelled in
+// metrics as the "experiment" variant against the "control", to measure changes on live traffic
+var experimentPercent = getEnvFloat("EXPERIMENT_PERCENT", 0)
+var experimentEncoding = getExperimentEncoding()
+var experimentLevel = getEnvInt("EXPERIMENT_LEVEL", -1)
+var experimentVariants = newBodyCache(getEnvSize("EXPERIMENT_CACHE_MEMORY", 64*1024*1024))
+
+// Only encodings there's an encoder for, so a response is never labelled with one it wasn't given
+func getExperimentEncoding() string {
+	encoding := getEnv("EXPERIMENT_ENCODING", "gzip")
+	if encoding != "gzip" && encoding != "br" && encoding != "identity" {
+		fmt.Println("⇨ invalid EXPERIMENT_ENCODING:", encoding, "(expected gzip, br or identity)")
+		os.Exit(-1)
+	}
+	return encoding
+}
+
+func getExperimentContent(route *Route, plain []byte) (string, []byte) {
+	var encoding string
+	var encode func([]byte, int) []byte
+	level := experimentLevel
+	switch experimentEncoding {
+	case "br":
+		encoding, encode = "br", brotliData
+		if level < 0 {
+			level = defaultLevels.Brotli
+		}
+	case "gzip":
+		encoding, encode = "gzip", gzipData
+		if level < 0 {
+			level = defaultLevels.Gzip
+		}
+	default:
+		return "", plain
+	}
+	if variant, exists := experimentVariants.Get(route.Hash); exists {
+		return encoding, variant.Plain
+	}
+	variant := encode(plain, level)
+	experimentVariants.Put(route.Hash, Content{Plain: variant})
+	return encoding, variant
+}
+
+func writeContent(ctx *fasthttp.RequestCtx, route *Route, routeContent Content) {
+	encoding, content := getEncodedContent(ctx, route, routeContent)
+	// Only responses that would be compressed, to clients accepting the alternate encoding, take part
+	if experimentPercent > 0 && encoding != "" && (experimentEncoding == "identity" || acceptsEncoding(ctx, experimentEncoding)) {
+		ctx.SetUserValue("variant", "control")
+		if rand.Float64()*100 < experimentPercent {
+			ctx.SetUserValue("variant", "experiment")
+			encoding, content = getExperimentContent(route, routeContent.Plain)
+		}
+	}
+	if encoding != "" {
+		ctx.Response.Header.Set("Content-Encoding", encoding)
+	}
+	fmt.Fprintf(ctx, "%s", content)
+}
+
+// Map status codes to the url paths of pages served for them, e.g. ERROR_PAGES=404=/errors/404.html
+func getErrorPages() map[int]string {
+	errorPages := make(map[int]string)
+	for _, mapping := range strings.Split(getEnv("ERROR_PAGES", ""), ",") {
+		if strings.TrimSpace(mapping) == "" {
+			continue
+		}
+		status, page, found := strings.Cut(strings.TrimSpace(mapping), "=")
+		code, err := strconv.Atoi(status)
+		if !found || err != nil || code < 400 || code > 599 {
+			fmt.Println("⇨ warning: invalid ERROR_PAGES mapping", mapping)
+			continue
+		}
+		if _, exists := resolveFile(page); !exists {
+			fmt.Println("⇨ warning: error page", page, "for", code, "not found")
+		}
+		errorPages[code] = page
+	}
+	return errorPages
+}
+
+var errorPages = getErrorPages()
+
+// Respond with the error page for a status, served like any other route, or a plain message
+func serveError(ctx *fasthttp.RequestCtx, status int) {
+	if page, exists := errorPages[status]; exists {
+		if route, exists := lookupRoute(page); exists && route.FilePath == "" {
+			if routeContent, err := getContent(route); err == nil {
+				ctx.Response.Header.Set("Content-Type", route.ContentType)
+				ctx.Response.Header.Set("Server", "nano-web")
+				writeContent(ctx, route, routeContent)
+				ctx.SetStatusCode(status)
+				return
+			}
+		}
+	}
+	ctx.Error(fasthttp.StatusMessage(status), status)
+}
+
+// SO_BINDTODEVICE on linux, which isn't in the syscall package on every platform
+const soBindToDevice = 0x19
+
+var bindDevice = getEnv("BIND_DEVICE", "")
+
+// Applied to listening sockets before they're bound
+func controlSocket(network string, address string, conn syscall.RawConn) error {
+	if bindDevice == "" {
+		return nil
+	}
+	if runtime.GOOS != "linux" {
+		return errors.New("BIND_DEVICE is only supported on linux")
+	}
+	var sockErr error
+	err := conn.Control(func(fd uintptr) {
+		sockErr = syscall.SetsockoptString(int(fd), syscall.SOL_SOCKET, soBindToDevice, bindDevice)
+	})
+	if err != nil {
+		return err
+	}
+	if errors.Is(sockErr, syscall.EPERM) {
+		return fmt.Errorf("binding to device %s requires CAP_NET_RAW: %w", bindDevice, sockErr)
+	}
+	if sockErr != nil {
+		return fmt.Errorf("binding to device %s: %w", bindDevice, sockErr)
+	}
+	return nil
+}
+
+// TCP level socket options on linux, which aren't in the syscall package on every platform
+const tcpKeepIdle = 0x4
+const tcpKeepIntvl = 0x5
+const tcpKeepCnt = 0x6
+const tcpUserTimeout = 0x12
+
+var tcpOptions = map[int]int{
+	tcpKeepIdle:    getEnvInt("TCP_KEEPALIVE_IDLE", 0),
+	tcpKeepIntvl:   getEnvInt("TCP_KEEPALIVE_INTERVAL", 0),
+	tcpKeepCnt:     getEnvInt("TCP_KEEPALIVE_COUNT", 0),
+	tcpUserTimeout: getEnvInt("TCP_USER_TIMEOUT", 0),
+}
+
+// Applies keepalive and user timeout options to each accepted connection, as Go
+// overrides the keepalive settings accepted sockets would inherit from the listener
+type tcpOptionsListener struct {
+	net.Listener
+}
+
+func (listener tcpOptionsListener) Accept() (net.Conn, error) {
+	conn, err := listener.Listener.Accept()
+	if err != nil {
+		return nil, err
+	}
+	tcpConn, ok := conn.(*net.TCPConn)
+	if !ok {
+		return conn, nil
+	}
+	rawConn, err := tcpConn.SyscallConn()
+	if err != nil {
+		return conn, nil
+	}
+	rawConn.Control(func(fd uintptr) {
+		for option, value := range tcpOptions {
+			if value > 0 {
+				syscall.SetsockoptInt(int(fd), syscall.IPPROTO_TCP, option, value)
+			}
+		}
+	})
+	return conn, nil
+}
 
-		route, err := makeRoute(path)
+func hasTcpOptions() bool {
+	for _, value := range tcpOptions {
+		if value > 0 {
+			return true
+		}
+	}
+	return false
+}
+
+// Comma separated addresses from LISTEN, or all interfaces on PORT
+func getListenAddrs() []string {
+	var addrs []string
+	for _, addr := range strings.Split(getEnv("LISTEN", ""), ",") {
+		addr = strings.TrimSpace(addr)
+		if addr != "" {
+			addrs = append(addrs, addr)
+		}
+	}
+	if len(addrs) == 0 {
+		addrs = append(addrs, ":"+getEnv("PORT", "80"))
+	}
+	return addrs
+}
+
+// Literal IPv4 and IPv6 addresses get a listener for just that family, so that
+// 0.0.0.0:80 and [::]:80 can be bound side by side
+func getListenNetwork(addr string) string {
+	host, _, err := net.SplitHostPort(addr)
+	if err != nil {
+		return "tcp"
+	}
+	ip := net.ParseIP(host)
+	if ip == nil {
+		return "tcp"
+	}
+	if ip.To4() != nil {
+		return "tcp4"
+	}
+	return "tcp6"
+}
+
+func listen(addr string) (net.Listener, error) {
+	listenConfig := net.ListenConfig{Control: controlSocket}
+	listener, err := listenConfig.Listen(context.Background(), getListenNetwork(addr), addr)
+	if err != nil {
+		return nil, err
+	}
+	if hasTcpOptions() {
+		if runtime.GOOS != "linux" {
+			listener.Close()
+			return nil, errors.New("TCP keepalive and user timeout options are only supported on linux")
+		}
+		return tcpOptionsListener{listener}, nil
+	}
+	return listener, nil
+}
+
+var metricsPath = getEnv("METRICS_PATH", "")
+
+// Upper bounds in seconds of the request duration histogram buckets, the last being +Inf
+var durationBuckets = []float64{0.0001, 0.00025, 0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1}
+
+type exemplar struct {
+	traceId   string
+	value     float64
+	timestamp float64
+}
+
+type requestLabels struct {
+	status   int
+	encoding string
+	class    string
+	variant  string
+}
+
+type bytesLabels struct {
+	encoding string
+	variant  string
+}
+
+type RequestClass struct {
+	Pattern *regexp.Regexp
+	Label   string
+}
+
+var classLabelPattern = regexp.MustCompile(`^[A-Za-z_][A-Za-z0-9_]*$`)
 
+// Path globs mapped to labels, e.g. "/users/*/avatar.png=user_avatar", so metrics and logs can
+// group requests without a label per path
+func getRequestClasses() []RequestClass {
+	var classes []RequestClass
+	for _, rule := range strings.Split(getEnv("REQUEST_CLASSES", ""), ",") {
+		rule = strings.TrimSpace(rule)
+		if rule == "" {
+			continue
+		}
+		index := strings.LastIndex(rule, "=")
+		if index == -1 || !classLabelPattern.MatchString(rule[index+1:]) {
+			fmt.Println("⇨ invalid rule in REQUEST_CLASSES:", rule)
+			os.Exit(-1)
+		}
+		compiled, err := compileGlob(rule[:index])
 		if err != nil {
-			fmt.Errorf("⇨ error making route for %s: %s", urlPath, err)
-			return nil
+			fmt.Println("⇨ invalid pattern in REQUEST_CLASSES:", rule)
+			os.Exit(-1)
 		}
+		classes = append(classes, RequestClass{compiled, rule[index+1:]})
+	}
+	return classes
+}
 
-		routes[urlPath] = route
+var requestClasses = getRequestClasses()
 
-		if info.Name() == "index.html" {
-			indexUrlPath := strings.Replace(urlPath, "/index.html", "", 1)
-			if indexUrlPath == "" {
-				indexUrlPath = "/"
-			}
-			fmt.Println("⇨ adding index", indexUrlPath, "→", path)
-			routes[indexUrlPath] = route
-			routes[indexUrlPath+"/"] = route
+// The label of the first rule matching a path, or "other"
+func classifyPath(urlPath string) string {
+	for _, class := range requestClasses {
+		if class.Pattern.MatchString(urlPath) {
+			return class.Label
 		}
-		fmt.Println("⇨ adding route", urlPath, "→", path)
+	}
+	return "other"
+}
 
-		return nil
-	})
+type Metrics struct {
+	lock          sync.Mutex
+	requests      map[requestLabels]int64
+	bytes         map[bytesLabels]int64
+	buckets       []int64
+	exemplars     []exemplar
+	durationSum   float64
+	durationCount int64
+	connections   map[string]int64
+}
+
+var metrics = Metrics{
+	requests:    make(map[requestLabels]int64),
+	bytes:       make(map[bytesLabels]int64),
+	buckets:     make([]int64, len(durationBuckets)+1),
+	exemplars:   make([]exemplar, len(durationBuckets)+1),
+	connections: make(map[string]int64),
+}
+
+func (metrics *Metrics) observeRequest(labels requestLabels, bytes int, duration float64, traceId string) {
+	bucket := sort.SearchFloat64s(durationBuckets, duration)
+	metrics.lock.Lock()
+	defer metrics.lock.Unlock()
+	metrics.requests[labels]++
+	metrics.bytes[bytesLabels{labels.encoding, labels.variant}] += int64(bytes)
+	metrics.buckets[bucket]++
+	metrics.durationSum += duration
+	metrics.durationCount++
+	if traceId != "" {
+		metrics.exemplars[bucket] = exemplar{traceId, duration, float64(time.Now().UnixNano()) / 1e9}
+	}
+}
+
+func (metrics *Metrics) observeConnection(family string) {
+	metrics.lock.Lock()
+	defer metrics.lock.Unlock()
+	metrics.connections[family]++
+}
+
+// Counts accepted connections per address family, for listeners bound to both
+type countingListener struct {
+	net.Listener
 }
 
-func getAcceptedEncoding(ctx *fasthttp.RequestCtx) string {
-	acceptEncoding := string(ctx.Request.Header.Peek("Accept-Encoding"))
-	if strings.Contains(acceptEncoding, "br") {
-		return "br"
+func (listener countingListener) Accept() (net.Conn, error) {
+	conn, err := listener.Listener.Accept()
+	if err != nil {
+		return nil, err
 	}
-	if strings.Contains(acceptEncoding, "gzip") {
-		return "gzip"
+	family := "ipv6"
+	if addr, ok := conn.RemoteAddr().(*net.TCPAddr); ok && addr.IP.To4() != nil {
+		family = "ipv4"
 	}
-	return ""
+	metrics.observeConnection(family)
+	return conn, nil
 }
 
-func getEncodedContent(acceptedEncoding string, content Content) (string, []byte) {
-	switch acceptedEncoding {
-	case "br":
-		if content.Brotli != nil {
-			return "br", content.Brotli
-		} else {
-			return "", content.Plain
+func withMetrics(next fasthttp.RequestHandler) fasthttp.RequestHandler {
+	return func(ctx *fasthttp.RequestCtx) {
+		next(ctx)
+		if string(ctx.Path()) == metricsPath {
+			return
 		}
-	case "gzip":
-		if content.Gzip != nil {
-			return "gzip", content.Gzip
+		encoding := string(ctx.Response.Header.Peek("Content-Encoding"))
+		if encoding == "" {
+			encoding = "identity"
+		}
+		bytes := ctx.Response.Header.ContentLength()
+		if !ctx.Response.IsBodyStream() {
+			bytes = len(ctx.Response.Body())
+		} else if bytes < 0 {
+			bytes = 0
+		}
+		class := ""
+		if len(requestClasses) > 0 {
+			class = classifyPath(string(ctx.Path()))
+		}
+		traceId, _ := ctx.UserValue("traceId").(string)
+		variant, _ := ctx.UserValue("variant").(string)
+		labels := requestLabels{ctx.Response.StatusCode(), encoding, class, variant}
+		metrics.observeRequest(labels, bytes, time.Since(ctx.Time()).Seconds(), traceId)
+	}
+}
+
+// Write the metrics in the Prometheus text format, or OpenMetrics (with exemplars) when accepted.
+// Metric names and labels are stable, see the README.
+func writeMetrics(ctx *fasthttp.RequestCtx) {
+	openMetrics := strings.Contains(string(ctx.Request.Header.Peek("Accept")), "application/openmetrics-text")
+	// OpenMetrics names counter families without the _total their samples have, Prometheus
+	// text names them after the samples
+	counterHeader := func(name string, help string) string {
+		if !openMetrics {
+			name += "_total"
+		}
+		return "# HELP " + name + " " + help + "\n# TYPE " + name + " counter\n"
+	}
+	var b strings.Builder
+
+	metrics.lock.Lock()
+	b.WriteString(counterHeader("nano_web_requests", "Requests served, by status and content encoding."))
+	requestLabelsSorted := make([]requestLabels, 0, len(metrics.requests))
+	for labels := range metrics.requests {
+		requestLabelsSorted = append(requestLabelsSorted, labels)
+	}
+	sort.Slice(requestLabelsSorted, func(i, j int) bool {
+		if requestLabelsSorted[i].status != requestLabelsSorted[j].status {
+			return requestLabelsSorted[i].status < requestLabelsSorted[j].status
+		}
+		if requestLabelsSorted[i].encoding != requestLabelsSorted[j].encoding {
+			return requestLabelsSorted[i].encoding < requestLabelsSorted[j].encoding
+		}
+		if requestLabelsSorted[i].class != requestLabelsSorted[j].class {
+			return requestLabelsSorted[i].class < requestLabelsSorted[j].class
+		}
+		return requestLabelsSorted[i].variant < requestLabelsSorted[j].variant
+	})
+	for _, labels := range requestLabelsSorted {
+		fmt.Fprintf(&b, "nano_web_requests_total{status=\"%d\",encoding=\"%s\"", labels.status, labels.encoding)
+		if labels.class != "" {
+			fmt.Fprintf(&b, ",class=\"%s\"", labels.class)
+		}
+		if labels.variant != "" {
+			fmt.Fprintf(&b, ",variant=\"%s\"", labels.variant)
+		}
+		fmt.Fprintf(&b, "} %d\n", metrics.requests[labels])
+	}
+
+	b.WriteString(counterHeader("nano_web_response_bytes", "Response body bytes sent, by content encoding."))
+	bytesLabelsSorted := make([]bytesLabels, 0, len(metrics.bytes))
+	for labels := range metrics.bytes {
+		bytesLabelsSorted = append(bytesLabelsSorted, labels)
+	}
+	sort.Slice(bytesLabelsSorted, func(i, j int) bool {
+		if bytesLabelsSorted[i].encoding != bytesLabelsSorted[j].encoding {
+			return bytesLabelsSorted[i].encoding < bytesLabelsSorted[j].encoding
+		}
+		return bytesLabelsSorted[i].variant < bytesLabelsSorted[j].variant
+	})
+	for _, labels := range bytesLabelsSorted {
+		if labels.variant != "" {
+			fmt.Fprintf(&b, "nano_web_response_bytes_total{encoding=\"%s\",variant=\"%s\"} %d\n", labels.encoding, labels.variant, metrics.bytes[labels])
 		} else {
-			return "", content.Plain
+			fmt.Fprintf(&b, "nano_web_response_bytes_total{encoding=\"%s\"} %d\n", labels.encoding, metrics.bytes[labels])
 		}
-	default:
-		return "", content.Plain
 	}
+
+	b.WriteString(counterHeader("nano_web_connections", "Connections accepted, by address family."))
+	for _, family := range sortedKeys(metrics.connections) {
+		fmt.Fprintf(&b, "nano_web_connections_total{family=\"%s\"} %d\n", family, metrics.connections[family])
+	}
+
+	b.WriteString("# HELP nano_web_request_duration_seconds Time spent handling requests.\n")
+	b.WriteString("# TYPE nano_web_request_duration_seconds histogram\n")
+	var cumulative int64
+	for i, count := range metrics.buckets {
+		cumulative += count
+		le := "+Inf"
+		if i < len(durationBuckets) {
+			le = strconv.FormatFloat(durationBuckets[i], 'f', -1, 64)
+		}
+		fmt.Fprintf(&b, "nano_web_request_duration_seconds_bucket{le=\"%s\"} %d", le, cumulative)
+		if example := metrics.exemplars[i]; openMetrics && example.traceId != "" {
+			fmt.Fprintf(&b, " # {trace_id=\"%s\"} %v %.3f", example.traceId, example.value, example.timestamp)
+		}
+		b.WriteString("\n")
+	}
+	fmt.Fprintf(&b, "nano_web_request_duration_seconds_sum %v\n", metrics.durationSum)
+	fmt.Fprintf(&b, "nano_web_request_duration_seconds_count %d\n", metrics.durationCount)
+	metrics.lock.Unlock()
+
+	routesLock.RLock()
+	routeCount := len(routes)
+	routesLock.RUnlock()
+	b.WriteString("# HELP nano_web_routes Routes currently served.\n")
+	b.WriteString("# TYPE nano_web_routes gauge\n")
+	fmt.Fprintf(&b, "nano_web_routes %d\n", routeCount)
+	b.WriteString("# HELP nano_web_degraded Whether the public directory is unavailable and the in-memory routes are served as they were.\n")
+	b.WriteString("# TYPE nano_web_degraded gauge\n")
+	degradedValue := 0
+	if degraded.Load() {
+		degradedValue = 1
+	}
+	fmt.Fprintf(&b, "nano_web_degraded %d\n", degradedValue)
+
+	if openMetrics {
+		b.WriteString("# EOF\n")
+		ctx.SetContentType("application/openmetrics-text; version=1.0.0; charset=utf-8")
+	} else {
+		ctx.SetContentType("text/plain; version=0.0.4; charset=utf-8")
+	}
+	ctx.SetBodyString(b.String())
 }
 
-func handler(ctx *fasthttp.RequestCtx) {
-	fmt.Println("⇨ request", string(ctx.Path()))
-	route, exists := routes[string(ctx.Path())]
-	if !exists {
-		if os.Getenv("SPA_MODE") == "1" {
-			route, exists = routes["/"]
-			if !exists {
-				ctx.Error("Not Found", fasthttp.StatusNotFound)
-				return
+func sortedKeys[K cmp.Ordered, V any](values map[K]V) []K {
+	keys := make([]K, 0, len(values))
+	for key := range values {
+		keys = append(keys, key)
+	}
+	slices.Sort(keys)
+	return keys
+}
+
+var sloTarget = getEnvFloat("SLO_TARGET", 0)
+var sloLatency = time.Duration(getEnvInt("SLO_LATENCY_MS", 0)) * time.Millisecond
+var sloBurnRate = getEnvFloat("SLO_BURN_RATE", 14.4)
+
+type sloWindow struct {
+	requests atomic.Int64
+	errors   atomic.Int64
+	slow     atomic.Int64
+}
+
+var slo sloWindow
+
+// Count requests towards the SLO window: 5xx responses against the availability target and
+// responses slower than SLO_LATENCY_MS against a p99 latency target
+func withSlo(next fasthttp.RequestHandler) fasthttp.RequestHandler {
+	return func(ctx *fasthttp.RequestCtx) {
+		next(ctx)
+		slo.requests.Add(1)
+		if ctx.Response.StatusCode() >= 500 {
+			slo.errors.Add(1)
+		}
+		if sloLatency > 0 && time.Since(ctx.Time()) > sloLatency {
+			slo.slow.Add(1)
+		}
+	}
+}
+
+// Log a warning event when a window burns error budget faster than SLO_BURN_RATE
+func monitorSlo(window time.Duration) {
+	for range time.Tick(window) {
+		requests := slo.requests.Swap(0)
+		failed := slo.errors.Swap(0)
+		slow := slo.slow.Swap(0)
+		if requests == 0 {
+			continue
+		}
+		if sloTarget > 0 && sloTarget < 1 {
+			burnRate := float64(failed) / float64(requests) / (1 - sloTarget)
+			if burnRate > sloBurnRate {
+				fmt.Printf("⇨ warning: slo_burn kind=availability burn_rate=%.1f window=%s requests=%d errors=%d target=%v\n",
+					burnRate, window, requests, failed, sloTarget)
+			}
+		}
+		if sloLatency > 0 {
+			burnRate := float64(slow) / float64(requests) / 0.01
+			if burnRate > sloBurnRate {
+				fmt.Printf("⇨ warning: slo_burn kind=latency burn_rate=%.1f window=%s requests=%d slow=%d target=p99<%s\n",
+					burnRate, window, requests, slow, sloLatency)
+			}
+		}
+	}
+}
+
+// Host headers requests are accepted for, e.g. "example.com,www.example.com,*.example.org",
+// guarding against DNS rebinding and host header cache poisoning when exposed directly
+func getAllowedHosts() []string {
+	var hosts []string
+	for _, host := range strings.Split(getEnv("ALLOWED_HOSTS", ""), ",") {
+		if host = strings.ToLower(strings.TrimSpace(host)); host != "" {
+			hosts = append(hosts, host)
+		}
+	}
+	return hosts
+}
+
+var allowedHosts = getAllowedHosts()
+
+func hostAllowed(host string) bool {
+	if name, _, err := net.SplitHostPort(host); err == nil {
+		host = name
+	}
+	host = strings.TrimSuffix(strings.ToLower(host), ".")
+	for _, allowed := range allowedHosts {
+		if host == allowed || (strings.HasPrefix(allowed, "*.") && strings.HasSuffix(host, allowed[1:])) {
+			return true
+		}
+	}
+	return false
+}
+
+// Reject requests for other hosts with 421, except readiness probes, which come by IP
+func withAllowedHosts(next fasthttp.RequestHandler) fasthttp.RequestHandler {
+	return func(ctx *fasthttp.RequestCtx) {
+		if !hostAllowed(string(ctx.Host())) && string(ctx.Path()) != readyPath {
+			ctx.Error(fasthttp.StatusMessage(fasthttp.StatusMisdirectedRequest), fasthttp.StatusMisdirectedRequest)
+			return
+		}
+		next(ctx)
+	}
+}
+
+// Origins allowed to make cross-origin requests, e.g. "https://app.example.com", or "*" for any
+func getCorsOrigins() []string {
+	var origins []string
+	for _, origin := range strings.Split(getEnv("CORS_ORIGIN", ""), ",") {
+		if origin = strings.TrimSuffix(strings.TrimSpace(origin), "/"); origin != "" {
+			origins = append(origins, origin)
+		}
+	}
+	return origins
+}
+
+var corsOrigins = getCorsOrigins()
+
+// Credentials need explicit origins, since echoing any origin would let every site read
+// responses with the visitor's cookies
+func getCorsCredentials() bool {
+	credentials := getEnv("CORS_CREDENTIALS", "0") == "1"
+	if credentials && slices.Contains(corsOrigins, "*") {
+		fmt.Println("⇨ CORS_CREDENTIALS=1 needs explicit origins in CORS_ORIGIN, not *")
+		os.Exit(-1)
+	}
+	return credentials
+}
+
+var corsCredentials = getCorsCredentials()
+
+// Headers answering every allowed preflight, built once so preflights never reach the routes
+func getCorsPreflight() [][2]string {
+	preflight := [][2]string{{"Access-Control-Allow-Methods", getEnv("CORS_METHODS", "GET, HEAD, OPTIONS")}}
+	if headers := getEnv("CORS_HEADERS", ""); headers != "" {
+		preflight = append(preflight, [2]string{"Access-Control-Allow-Headers", headers})
+	}
+	if maxAge := getEnvInt("CORS_MAX_AGE", 600); maxAge > 0 {
+		preflight = append(preflight, [2]string{"Access-Control-Max-Age", strconv.Itoa(maxAge)})
+	}
+	return preflight
+}
+
+var corsPreflight = getCorsPreflight()
+
+// The Access-Control-Allow-Origin for a request's Origin, or "" if it isn't allowed
+func corsAllowOrigin(origin string) string {
+	if origin == "" {
+		return ""
+	}
+	for _, allowed := range corsOrigins {
+		if allowed == "*" {
+			return "*"
+		}
+		if allowed == origin {
+			return origin
+		}
+	}
+	return ""
+}
+
+// Add a request header the response depends on to Vary
+func addVary(ctx *fasthttp.RequestCtx, name string) {
+	if vary := ctx.Response.Header.Peek("Vary"); len(vary) > 0 {
+		ctx.Response.Header.Set("Vary", string(vary)+", "+name)
+	} else {
+		ctx.Response.Header.Set("Vary", name)
+	}
+}
+
+// Answer preflights, and add CORS headers once the response is otherwise complete, as error
+// responses reset the headers
+func withCors(next fasthttp.RequestHandler) fasthttp.RequestHandler {
+	return func(ctx *fasthttp.RequestCtx) {
+		allowOrigin := corsAllowOrigin(string(ctx.Request.Header.Peek("Origin")))
+		preflight := ctx.IsOptions() && len(ctx.Request.Header.Peek("Access-Control-Request-Method")) > 0
+		if preflight {
+			ctx.SetStatusCode(fasthttp.StatusNoContent)
+			if allowOrigin != "" {
+				for _, header := range corsPreflight {
+					ctx.Response.Header.Set(header[0], header[1])
+				}
 			}
 		} else {
-			ctx.Error("Not Found", fasthttp.StatusNotFound)
+			next(ctx)
+		}
+		if allowOrigin != "*" {
+			addVary(ctx, "Origin")
+		}
+		if allowOrigin != "" {
+			ctx.Response.Header.Set("Access-Control-Allow-Origin", allowOrigin)
+			if corsCredentials {
+				ctx.Response.Header.Set("Access-Control-Allow-Credentials", "true")
+			}
+		}
+	}
+}
+
+// Requests handled at once, beyond which OVERLOAD_POLICY applies, or 0 for no limit
+var maxConcurrency = getEnvInt("MAX_CONCURRENCY", 0)
+
+// What happens to requests over MAX_CONCURRENCY: "close" drops the connection, "503" serves the
+// ERROR_PAGES page for 503, and "shed" also turns away assets at random past half the limit
+func getOverloadPolicy() string {
+	policy := getEnv("OVERLOAD_POLICY", "503")
+	if policy != "close" && policy != "503" && policy != "shed" {
+		fmt.Println("⇨ invalid OVERLOAD_POLICY:", policy)
+		os.Exit(-1)
+	}
+	return policy
+}
+
+var overloadPolicy = getOverloadPolicy()
+
+// Connections with a request in progress, counted from the server's connection states so a
+// response still being written to a slow client counts until it's done, not just the handler
+var activeConns atomic.Int64
+var connStates sync.Map
+
+func trackConnState(conn net.Conn, state fasthttp.ConnState) {
+	previous, _ := connStates.Load(conn)
+	wasActive := previous == fasthttp.StateActive
+	if state == fasthttp.StateClosed || state == fasthttp.StateHijacked {
+		connStates.Delete(conn)
+	} else {
+		connStates.Store(conn, state)
+	}
+	if isActive := state == fasthttp.StateActive; isActive && !wasActive {
+		activeConns.Add(1)
+	} else if !isActive && wasActive {
+		activeConns.Add(-1)
+	}
+}
+
+func newServer(handler fasthttp.RequestHandler) *fasthttp.Server {
+	server := &fasthttp.Server{Handler: handler}
+	if maxConcurrency > 0 {
+		server.ConnState = trackConnState
+	}
+	return server
+}
+
+// Whether a request is for an HTML page, which shedding protects, rather than an asset
+func isPageRequest(urlPath string) bool {
+	if route, exists := getRoute(urlPath); exists {
+		return route.ContentType == "text/html"
+	}
+	return !strings.Contains(path.Base(urlPath), ".")
+}
+
+// Shed assets with a probability rising from 0 at half the limit to 1 at the limit, so pages
+// keep loading while large downloads back off
+func shouldShed(urlPath string, current int64) bool {
+	half := int64(maxConcurrency / 2)
+	if current <= half || isPageRequest(urlPath) {
+		return false
+	}
+	return rand.Float64() < float64(current-half)/float64(int64(maxConcurrency)-half)
+}
+
+func withOverload(next fasthttp.RequestHandler) fasthttp.RequestHandler {
+	return func(ctx *fasthttp.RequestCtx) {
+		current := activeConns.Load()
+		overloaded := current > int64(maxConcurrency)
+		if !overloaded && overloadPolicy == "shed" {
+			overloaded = shouldShed(string(ctx.Path()), current)
+		}
+		if !overloaded {
+			next(ctx)
+			return
+		}
+		if overloadPolicy == "close" {
+			// Hijacking without a response leaves the server to close the connection once
+			// the no-op handler returns
+			ctx.HijackSetNoResponse(true)
+			ctx.Hijack(func(net.Conn) {})
 			return
 		}
+		ctx.Response.Header.Set("Retry-After", "1")
+		ctx.SetConnectionClose()
+		serveError(ctx, fasthttp.StatusServiceUnavailable)
 	}
+}
 
-	ctx.Response.Header.Set("Content-Type", route.ContentType)
-	ctx.Response.Header.Set("Server", "nano-web")
-	ctx.Response.Header.Set("Last-Modified", route.LastModified)
-	acceptedEncoding := getAcceptedEncoding(ctx)
-	encoding, content := getEncodedContent(acceptedEncoding, route.Content)
-	if encoding != "" {
-		ctx.Response.Header.Set("Content-Encoding", encoding)
+// Print a machine readable summary line at readiness with STARTUP_SUMMARY=json, so deployment
+// tooling can assert on the serving configuration
+var startupSummary = getEnv("STARTUP_SUMMARY", "")
+
+// The addresses listened on, sent once they're all bound
+var listenersBound = make(chan []string, 1)
+
+type StartupSummary struct {
+	Event      string           `json:"event"`
+	Routes     int              `json:"routes"`
+	Bytes      map[string]int64 `json:"bytes"`
+	Backend    string           `json:"backend"`
+	Listeners  []string         `json:"listeners"`
+	Features   []string         `json:"features"`
+	Generation int              `json:"generation"`
+}
+
+func printStartupSummary(listeners []string) {
+	summary := StartupSummary{
+		Event:     "ready",
+		Bytes:     map[string]int64{"identity": 0, "gzip": 0, "br": 0},
+		Backend:   "fasthttp",
+		Listeners: listeners,
+		Features:  []string{},
 	}
-	fmt.Fprintf(ctx, "%s", content)
+	seen := make(map[*Route]bool)
+	routesLock.RLock()
+	summary.Routes = len(routes)
+	for _, route := range routes {
+		if seen[route] {
+			continue
+		}
+		seen[route] = true
+		summary.Bytes["identity"] += int64(len(route.Content.Plain))
+		summary.Bytes["gzip"] += int64(len(route.Content.Gzip))
+		summary.Bytes["br"] += int64(len(route.Content.Brotli))
+	}
+	routesLock.RUnlock()
+	for name, enabled := range map[string]bool{
+		"dev_mode":              devMode,
+		"spa":                   len(spaMounts) > 0,
+		"clean_urls":            cleanUrls,
+		"autoindex":             autoindex,
+		"archives":              serveArchives,
+		"origin":                originUrl != "",
+		"git_refs":              len(gitRefs) > 0,
+		"lazy_compress":         lazyCompress,
+		"lazy_populate":         lazyPopulate,
+		"background_recompress": backgroundRecompress,
+		"route_cache":           routeCachePath != "",
+		"metrics":               metricsPath != "",
+		"admin":                 adminToken != "",
+		"strict":                strict,
+		"max_concurrency":       maxConcurrency > 0,
+	} {
+		if enabled {
+			summary.Features = append(summary.Features, name)
+		}
+	}
+	sort.Strings(summary.Features)
+	generations.lock.Lock()
+	summary.Generation = generations.id
+	generations.lock.Unlock()
+	dat, _ := json.Marshal(summary)
+	fmt.Println(string(dat))
 }
 
 func main() {
-	addr := ":" + getEnv("PORT", "80")
-	populateRoutes(routes)
+	if len(os.Args) > 1 && os.Args[1] == "validate" {
+		validateAgainstRunning(os.Args[2:])
+		return
+	}
+	if len(os.Args) > 1 && os.Args[1] == "export" {
+		exportSnapshot(os.Args[2:])
+		return
+	}
+	if len(os.Args) > 1 && os.Args[1] == "selftest" {
+		selftest(os.Args[2:])
+		return
+	}
+	if len(os.Args) > 1 && os.Args[1] == "bench" {
+		bench(os.Args[2:])
+		return
+	}
+	if len(os.Args) > 1 && os.Args[1] == "report" {
+		report(os.Args[2:])
+		return
+	}
+	if len(os.Args) > 1 && os.Args[1] == "import" {
+		importConfig(os.Args[2:])
+		return
+	}
+	checkConfigSchema()
+	checkPublicDir()
+	printModeBanner()
+	go handleReload()
+	go scheduleRoutes()
+	go monitorPublicDirs(time.Duration(getEnvInt("PUBLIC_DIR_CHECK_INTERVAL", 5)) * time.Second)
+	if verifyInterval := getEnvInt("VERIFY_INTERVAL", 0); verifyInterval > 0 {
+		go monitorDrift(time.Duration(verifyInterval)*time.Second, getEnvInt("VERIFY_SAMPLE", 100))
+	}
+	if originUrl != "" {
+		go originCache.sweep()
+	}
+	files := preflightRoutes()
+	// Accept connections straight away, responding 503 (or with LAZY_POPULATE reading from
+	// disk) until the populated routes are swapped in
+	go func() {
+		pending := make(Routes)
+		populateRoutes(pending, files)
+		routesLock.Lock()
+		routes = pending
+		routesLock.Unlock()
+		populated.Store(true)
+		fmt.Println("⇨ ready")
+		recordGeneration()
+		if startupSummary == "json" {
+			printStartupSummary(<-listenersBound)
+		}
+		if backgroundRecompress {
+			recompressRoutes()
+		}
+	}()
+	if devMode {
+		go watchRoutes(time.Duration(getEnvInt("DEV_WATCH_INTERVAL", 250)) * time.Millisecond)
+	}
 	// fmt.Printf("⇨ routes:\n")
 	// pp.Print(routes)
-	fasthttp.ListenAndServe(addr, handler)
+	var requestHandler fasthttp.RequestHandler = handler
+	if mirrorUrl != "" {
+		requestHandler = withMirror(requestHandler)
+	}
+	if capturePath := getEnv("CAPTURE_FILE", ""); capturePath != "" {
+		requestHandler = withCapture(requestHandler, newJsonLines(capturePath))
+	}
+	if len(authProviders) > 0 {
+		requestHandler = withAuth(requestHandler)
+	}
+	if maxConcurrency > 0 {
+		requestHandler = withOverload(requestHandler)
+	}
+	if len(corsOrigins) > 0 {
+		requestHandler = withCors(requestHandler)
+	}
+	if len(allowedHosts) > 0 {
+		requestHandler = withAllowedHosts(requestHandler)
+	}
+	if metricsPath != "" {
+		requestHandler = withMetrics(requestHandler)
+	}
+	if accessLogPath := getEnv("ACCESS_LOG", ""); accessLogPath != "" {
+		requestHandler = withAccessLog(requestHandler, newJsonLines(accessLogPath))
+	}
+	if sloTarget > 0 || sloLatency > 0 {
+		requestHandler = withSlo(requestHandler)
+		go monitorSlo(time.Duration(getEnvInt("SLO_WINDOW", 300)) * time.Second)
+	}
+
+	var listeners []net.Listener
+	for _, addr := range getListenAddrs() {
+		listener, err := listen(addr)
+		if errors.Is(err, syscall.EADDRINUSE) {
+			fmt.Println("⇨ address already in use, is another server listening on", addr+"?")
+			os.Exit(exitPortInUse)
+		}
+		if errors.Is(err, os.ErrPermission) {
+			fmt.Println("⇨ permission denied listening on", addr+", ports below 1024 need CAP_NET_BIND_SERVICE")
+			os.Exit(exitPermissionDenied)
+		}
+		if err != nil {
+			fmt.Println("⇨ error listening on", addr+":", err)
+			os.Exit(-1)
+		}
+		fmt.Println("⇨ listening on", listener.Addr())
+		if metricsPath != "" {
+			listener = countingListener{listener}
+		}
+		listeners = append(listeners, listener)
+	}
+	addrs := make([]string, len(listeners))
+	for i, listener := range listeners {
+		addrs[i] = listener.Addr().String()
+	}
+	listenersBound <- addrs
+	server := newServer(requestHandler)
+	for _, listener := range listeners[1:] {
+		go server.Serve(listener)
+	}
+	server.Serve(listeners[0])
 }
@@ -0,0 +1,495 @@
+package main
+
+import (
+	"crypto"
+	"crypto/ecdsa"
+	"crypto/elliptic"
+	"crypto/hmac"
+	"crypto/rand"
+	"crypto/rsa"
+	"crypto/sha256"
+	"crypto/x509"
+	"encoding/base64"
+	"encoding/json"
+	"net"
+	"os"
+	"path/filepath"
+	"slices"
+	"strings"
+	"testing"
+	"time"
+
+	"github.com/valyala/fasthttp"
+	"github.com/valyala/fasthttp/fasthttputil"
+	"golang.org/x/crypto/bcrypt"
+)
+
+// A server behind withOverload in memory, whose /slow requests hold their connection until
+// the test ends
+type overloadServer struct {
+	client  *fasthttp.Client
+	started chan struct{}
+	release chan struct{}
+}
+
+func startOverloadServer(t *testing.T, limit int, policy string) *overloadServer {
+	previousLimit, previousPolicy := maxConcurrency, overloadPolicy
+	maxConcurrency, overloadPolicy = limit, policy
+	s := &overloadServer{started: make(chan struct{}), release: make(chan struct{})}
+	listener := fasthttputil.NewInmemoryListener()
+	go newServer(withOverload(func(ctx *fasthttp.RequestCtx) {
+		if string(ctx.Path()) == "/slow" {
+			s.started <- struct{}{}
+			<-s.release
+		}
+		ctx.SetBodyString("ok")
+	})).Serve(listener)
+	s.client = &fasthttp.Client{Dial: func(string) (net.Conn, error) { return listener.Dial() }}
+	t.Cleanup(func() {
+		close(s.release)
+		// Let held requests finish so the next test starts from no active connections
+		for deadline := time.Now().Add(time.Second); activeConns.Load() > 0 && time.Now().Before(deadline); {
+			time.Sleep(time.Millisecond)
+		}
+		listener.Close()
+		maxConcurrency, overloadPolicy = previousLimit, previousPolicy
+	})
+	return s
+}
+
+func (s *overloadServer) hold() {
+	go s.client.Get(nil, "http://nano-web/slow")
+	<-s.started
+}
+
+func (s *overloadServer) get(urlPath string) (int, error) {
+	status, _, err := s.client.Get(nil, "http://nano-web"+urlPath)
+	return status, err
+}
+
+func TestOverloadServesUnderLimit(t *testing.T) {
+	s := startOverloadServer(t, 2, "503")
+	s.hold()
+	if status, err := s.get("/page"); err != nil || status != fasthttp.StatusOK {
+		t.Fatalf("expected 200 under the limit, got %d %v", status, err)
+	}
+}
+
+func TestOverloadRespondsUnavailablePastLimit(t *testing.T) {
+	s := startOverloadServer(t, 1, "503")
+	s.hold()
+	if status, err := s.get("/page"); err != nil || status != fasthttp.StatusServiceUnavailable {
+		t.Fatalf("expected 503 past the limit, got %d %v", status, err)
+	}
+}
+
+func TestOverloadClosesPastLimit(t *testing.T) {
+	s := startOverloadServer(t, 1, "close")
+	s.hold()
+	if status, err := s.get("/page"); err == nil {
+		t.Fatalf("expected the connection to be closed past the limit, got %d", status)
+	}
+}
+
+func TestOverloadShedsAssetsBeforePages(t *testing.T) {
+	s := startOverloadServer(t, 2, "shed")
+	s.hold()
+	if status, err := s.get("/app.js"); err != nil || status != fasthttp.StatusServiceUnavailable {
+		t.Fatalf("expected assets to be shed at the limit, got %d %v", status, err)
+	}
+	if status, err := s.get("/about"); err != nil || status != fasthttp.StatusOK {
+		t.Fatalf("expected pages to be served below the limit, got %d %v", status, err)
+	}
+}
+
+// Serve files from a temporary public dir, with their routes built as at startup
+func serveFiles(t *testing.T, files map[string]string) {
+	dir := t.TempDir()
+	previousDirs := publicDirs
+	publicDirs = []string{dir}
+	testRoutes := make(Routes)
+	for name, body := range files {
+		path := filepath.Join(dir, filepath.FromSlash(name))
+		if err := os.MkdirAll(filepath.Dir(path), 0755); err != nil {
+			t.Fatal(err)
+		}
+		if err := os.WriteFile(path, []byte(body), 0644); err != nil {
+			t.Fatal(err)
+		}
+	}
+	for name := range files {
+		path := filepath.Join(dir, filepath.FromSlash(name))
+		route, err := makeRoute(path)
+		if err != nil {
+			t.Fatal(err)
+		}
+		addRoute(testRoutes, getUrlPath(path), path, route)
+	}
+	routesLock.Lock()
+	previousRoutes := routes
+	routes = testRoutes
+	routesLock.Unlock()
+	wasPopulated := populated.Swap(true)
+	t.Cleanup(func() {
+		routesLock.Lock()
+		routes = previousRoutes
+		routesLock.Unlock()
+		populated.Store(wasPopulated)
+		publicDirs = previousDirs
+	})
+}
+
+// Run a request through the handler, returning its response
+func request(urlPath string, headers map[string]string) *fasthttp.Response {
+	ctx := &fasthttp.RequestCtx{}
+	ctx.Request.SetRequestURI("http://nano-web" + urlPath)
+	for name, value := range headers {
+		ctx.Request.Header.Set(name, value)
+	}
+	handler(ctx)
+	return &ctx.Response
+}
+
+func TestArchivesServedWithoutContentEncoding(t *testing.T) {
+	archives := []struct {
+		name        string
+		contentType string
+	}{
+		{"release.zip", "application/zip"},
+		{"release.tar", "application/x-tar"},
+		{"release.tar.gz", "application/gzip"},
+		{"release.tgz", "application/gzip"},
+		{"release.tar.bz2", "application/x-bzip2"},
+		{"release.tar.xz", "application/x-xz"},
+		{"release.tar.zst", "application/zstd"},
+		{"release.7z", "application/x-7z-compressed"},
+		{"bundle.js.br", "application/x-brotli"},
+	}
+	files := make(map[string]string)
+	for _, archive := range archives {
+		// Compressible, so only the content type keeps it from being compressed again
+		files[archive.name] = strings.Repeat("not really an archive ", 200)
+	}
+	serveFiles(t, files)
+	for _, archive := range archives {
+		t.Run(archive.name, func(t *testing.T) {
+			res := request("/"+archive.name, map[string]string{"Accept-Encoding": "br, gzip"})
+			if res.StatusCode() != fasthttp.StatusOK {
+				t.Fatalf("expected 200, got %d", res.StatusCode())
+			}
+			if contentType := string(res.Header.ContentType()); contentType != archive.contentType {
+				t.Errorf("expected Content-Type %s, got %s", archive.contentType, contentType)
+			}
+			if encoding := res.Header.Peek("Content-Encoding"); len(encoding) > 0 {
+				t.Errorf("expected no Content-Encoding, got %s", encoding)
+			}
+		})
+	}
+}
+
+// The path and query a response redirects to
+func location(res *fasthttp.Response) string {
+	return strings.TrimPrefix(string(res.Header.Peek("Location")), "http://nano-web")
+}
+
+// Trust the given signing keys, as if just fetched from the issuer's JWKS
+func useOidcKeys(t *testing.T, keys map[string]crypto.PublicKey) {
+	previousIssuer, previousClientId := oidcIssuer, oidcClientId
+	oidcIssuer, oidcClientId = "https://issuer.example.com", "nano-web"
+	oidcKeys.lock.Lock()
+	previousKeys, previousFetched := oidcKeys.keys, oidcKeys.fetched
+	oidcKeys.keys, oidcKeys.fetched = keys, time.Now()
+	oidcKeys.lock.Unlock()
+	t.Cleanup(func() {
+		oidcIssuer, oidcClientId = previousIssuer, previousClientId
+		oidcKeys.lock.Lock()
+		oidcKeys.keys, oidcKeys.fetched = previousKeys, previousFetched
+		oidcKeys.lock.Unlock()
+	})
+}
+
+func encodeJwtPart(value any) string {
+	dat, _ := json.Marshal(value)
+	return base64.RawURLEncoding.EncodeToString(dat)
+}
+
+// A JWT of the header and claims, signed by sign over its signing input
+func makeJwt(header map[string]string, claims map[string]any, sign func(input []byte) []byte) string {
+	input := encodeJwtPart(header) + "." + encodeJwtPart(claims)
+	return input + "." + base64.RawURLEncoding.EncodeToString(sign([]byte(input)))
+}
+
+func TestVerifyIdToken(t *testing.T) {
+	rsaKey, err := rsa.GenerateKey(rand.Reader, 2048)
+	if err != nil {
+		t.Fatal(err)
+	}
+	ecKey, err := ecdsa.GenerateKey(elliptic.P256(), rand.Reader)
+	if err != nil {
+		t.Fatal(err)
+	}
+	useOidcKeys(t, map[string]crypto.PublicKey{"rsa": &rsaKey.PublicKey, "ec": &ecKey.PublicKey})
+
+	signRs256 := func(input []byte) []byte {
+		digest := sha256.Sum256(input)
+		signature, _ := rsa.SignPKCS1v15(rand.Reader, rsaKey, crypto.SHA256, digest[:])
+		return signature
+	}
+	signEs256 := func(input []byte) []byte {
+		digest := sha256.Sum256(input)
+		r, s, _ := ecdsa.Sign(rand.Reader, ecKey, digest[:])
+		return append(r.FillBytes(make([]byte, 32)), s.FillBytes(make([]byte, 32))...)
+	}
+	// An HMAC keyed with the public key, which verifiers trusting the token's alg would accept
+	signHs256 := func(input []byte) []byte {
+		publicKey, _ := x509.MarshalPKIXPublicKey(&rsaKey.PublicKey)
+		mac := hmac.New(sha256.New, publicKey)
+		mac.Write(input)
+		return mac.Sum(nil)
+	}
+	unsigned := func(input []byte) []byte { return nil }
+	claimsWith := func(changes map[string]any) map[string]any {
+		claims := map[string]any{
+			"iss":   "https://issuer.example.com",
+			"sub":   "alice",
+			"aud":   "nano-web",
+			"exp":   time.Now().Add(time.Minute).Unix(),
+			"nonce": "nonce",
+		}
+		for name, value := range changes {
+			claims[name] = value
+		}
+		return claims
+	}
+	valid := makeJwt(map[string]string{"alg": "RS256", "kid": "rsa"}, claimsWith(nil), signRs256)
+	parts := strings.Split(valid, ".")
+	tampered := parts[0] + "." + encodeJwtPart(claimsWith(map[string]any{"sub": "mallory"})) + "." + parts[2]
+
+	tests := []struct {
+		name  string
+		token string
+		valid bool
+	}{
+		{"RS256", valid, true},
+		{"ES256", makeJwt(map[string]string{"alg": "ES256", "kid": "ec"}, claimsWith(nil), signEs256), true},
+		{"audience list", makeJwt(map[string]string{"alg": "RS256", "kid": "rsa"}, claimsWith(map[string]any{"aud": []string{"other", "nano-web"}}), signRs256), true},
+		{"alg none", makeJwt(map[string]string{"alg": "none", "kid": "rsa"}, claimsWith(nil), unsigned), false},
+		{"HS256 keyed with the public key", makeJwt(map[string]string{"alg": "HS256", "kid": "rsa"}, claimsWith(nil), signHs256), false},
+		{"alg not matching the key", makeJwt(map[string]string{"alg": "ES256", "kid": "rsa"}, claimsWith(nil), signRs256), false},
+		{"unknown kid", makeJwt(map[string]string{"alg": "RS256", "kid": "other"}, claimsWith(nil), signRs256), false},
+		{"tampered claims", tampered, false},
+		{"expired", makeJwt(map[string]string{"alg": "RS256", "kid": "rsa"}, claimsWith(map[string]any{"exp": time.Now().Add(-time.Minute).Unix()}), signRs256), false},
+		{"other issuer", makeJwt(map[string]string{"alg": "RS256", "kid": "rsa"}, claimsWith(map[string]any{"iss": "https://other.example.com"}), signRs256), false},
+		{"other audience", makeJwt(map[string]string{"alg": "RS256", "kid": "rsa"}, claimsWith(map[string]any{"aud": "other"}), signRs256), false},
+		{"other nonce", makeJwt(map[string]string{"alg": "RS256", "kid": "rsa"}, claimsWith(map[string]any{"nonce": "other"}), signRs256), false},
+		{"malformed", "not.a-token", false},
+	}
+	for _, test := range tests {
+		t.Run(test.name, func(t *testing.T) {
+			claims, err := verifyIdToken(test.token, "nonce")
+			if test.valid && (err != nil || claims.Subject != "alice") {
+				t.Fatalf("expected the token to be accepted, got %v", err)
+			}
+			if !test.valid && err == nil {
+				t.Fatal("expected the token to be rejected")
+			}
+		})
+	}
+}
+
+func TestBasicAuthChecksBcryptHashes(t *testing.T) {
+	hash, err := bcrypt.GenerateFromPassword([]byte("secret"), bcrypt.MinCost)
+	if err != nil {
+		t.Fatal(err)
+	}
+	auth := &BasicAuth{Prefix: "/private", Users: map[string]string{"alice": string(hash)}}
+	basic := func(credentials string) string {
+		return "Basic " + base64.StdEncoding.EncodeToString([]byte(credentials))
+	}
+	tests := []struct {
+		name          string
+		authorization string
+		result        AuthResult
+	}{
+		{"correct password", basic("alice:secret"), AuthAllow},
+		{"wrong password", basic("alice:wrong"), AuthChallenge},
+		{"the hash as the password", basic("alice:" + string(hash)), AuthChallenge},
+		{"unknown user", basic("bob:secret"), AuthChallenge},
+		{"no credentials", "", AuthChallenge},
+		{"other scheme", "Bearer secret", AuthChallenge},
+	}
+	for _, test := range tests {
+		t.Run(test.name, func(t *testing.T) {
+			ctx := &fasthttp.RequestCtx{}
+			if test.authorization != "" {
+				ctx.Request.Header.Set("Authorization", test.authorization)
+			}
+			if result, _ := auth.Validate(ctx); result != test.result {
+				t.Fatalf("expected %d, got %d", test.result, result)
+			}
+		})
+	}
+	for urlPath, protected := range map[string]bool{"/private": true, "/private/report.pdf": true, "/privateer": false, "/": false} {
+		if auth.Protects(urlPath) != protected {
+			t.Errorf("expected Protects(%s) to be %t", urlPath, protected)
+		}
+	}
+}
+
+func TestCompileGlob(t *testing.T) {
+	tests := []struct {
+		pattern string
+		path    string
+		matches bool
+	}{
+		{"*.js", "app.js", true},
+		{"*.js", "assets/app.js", false},
+		{"assets/*.js", "assets/app.js", true},
+		{"assets/*.js", "assets/vendor/app.js", false},
+		{"assets/**", "assets/vendor/app.js", true},
+		{"**/*.map", "assets/vendor/app.js.map", true},
+		{"?.txt", "a.txt", true},
+		{"?.txt", "ab.txt", false},
+		{"?.txt", "/.txt", false},
+		{"app.js", "appxjs", false},
+		{"file[1].txt", "file[1].txt", true},
+		{"file[1].txt", "file1.txt", false},
+		{"users/*/avatar.png", "users/42/avatar.png", true},
+		{"users/*/avatar.png", "users/42/avatar.png.bak", false},
+	}
+	for _, test := range tests {
+		compiled, err := compileGlob(test.pattern)
+		if err != nil {
+			t.Fatalf("compiling %s: %v", test.pattern, err)
+		}
+		if compiled.MatchString(test.path) != test.matches {
+			t.Errorf("expected %s matching %s to be %t", test.pattern, test.path, test.matches)
+		}
+	}
+}
+
+func TestParseFrontMatter(t *testing.T) {
+	frontMatter, body := parseFrontMatter([]byte("---\r\ntitle: \"Hello: world\"\r\ntags: [a, 'b', ]\r\nredirect_from:\r\n  - /old\r\n  - \"/older\"\r\ncache_control: public, max-age=60\r\n---\r\n<h1>Hi</h1>\r\n"))
+	expected := map[string][]string{
+		"title":         {"Hello: world"},
+		"tags":          {"a", "b"},
+		"redirect_from": {"/old", "/older"},
+		"cache_control": {"public, max-age=60"},
+	}
+	if len(frontMatter) != len(expected) {
+		t.Fatalf("expected %v, got %v", expected, frontMatter)
+	}
+	for key, values := range expected {
+		if !slices.Equal(frontMatter[key], values) {
+			t.Errorf("expected %s to be %v, got %v", key, values, frontMatter[key])
+		}
+	}
+	if string(body) != "<h1>Hi</h1>\n" {
+		t.Errorf("expected the body after the front matter, got %q", body)
+	}
+
+	for _, dat := range []string{"<h1>No front matter</h1>", "---\ntitle: unterminated\n<h1>Hi</h1>", "\n---\ntitle: not at the start\n---\n"} {
+		frontMatter, body := parseFrontMatter([]byte(dat))
+		if frontMatter != nil || string(body) != dat {
+			t.Errorf("expected %q to be left as it is, got %v and %q", dat, frontMatter, body)
+		}
+	}
+}
+
+func TestServeFileRanges(t *testing.T) {
+	path := filepath.Join(t.TempDir(), "video.mp4")
+	if err := os.WriteFile(path, []byte("0123456789"), 0644); err != nil {
+		t.Fatal(err)
+	}
+	tests := []struct {
+		rangeHeader  string
+		status       int
+		body         string
+		contentRange string
+	}{
+		{"", fasthttp.StatusOK, "0123456789", ""},
+		{"bytes=2-5", fasthttp.StatusPartialContent, "2345", "bytes 2-5/10"},
+		{"bytes=7-", fasthttp.StatusPartialContent, "789", "bytes 7-9/10"},
+		{"bytes=-3", fasthttp.StatusPartialContent, "789", "bytes 7-9/10"},
+		{"bytes=5-100", fasthttp.StatusPartialContent, "56789", "bytes 5-9/10"},
+		{"bytes=20-30", fasthttp.StatusRequestedRangeNotSatisfiable, "", "bytes */10"},
+		{"items=0-1", fasthttp.StatusRequestedRangeNotSatisfiable, "", "bytes */10"},
+	}
+	for _, test := range tests {
+		t.Run(test.rangeHeader, func(t *testing.T) {
+			ctx := &fasthttp.RequestCtx{}
+			if test.rangeHeader != "" {
+				ctx.Request.Header.Set("Range", test.rangeHeader)
+			}
+			serveFile(ctx, path)
+			if ctx.Response.StatusCode() != test.status {
+				t.Fatalf("expected %d, got %d", test.status, ctx.Response.StatusCode())
+			}
+			if body := string(ctx.Response.Body()); body != test.body {
+				t.Errorf("expected body %q, got %q", test.body, body)
+			}
+			if contentRange := string(ctx.Response.Header.Peek("Content-Range")); contentRange != test.contentRange {
+				t.Errorf("expected Content-Range %q, got %q", test.contentRange, contentRange)
+			}
+		})
+	}
+}
+
+func TestCleanUrlRedirects(t *testing.T) {
+	previous := cleanUrls
+	cleanUrls = true
+	t.Cleanup(func() { cleanUrls = previous })
+	serveFiles(t, map[string]string{"about.html": "<h1>About</h1>", "docs/index.html": "<h1>Docs</h1>"})
+
+	tests := []struct {
+		urlPath  string
+		status   int
+		location string
+	}{
+		{"/about.html", fasthttp.StatusMovedPermanently, "/about"},
+		{"/about.html?ref=nav", fasthttp.StatusMovedPermanently, "/about?ref=nav"},
+		{"/docs/index.html", fasthttp.StatusMovedPermanently, "/docs/"},
+		{"/about", fasthttp.StatusOK, ""},
+		{"/docs/", fasthttp.StatusOK, ""},
+		{"/missing.html", fasthttp.StatusNotFound, ""},
+	}
+	for _, test := range tests {
+		res := request(test.urlPath, nil)
+		if res.StatusCode() != test.status || location(res) != test.location {
+			t.Errorf("%s: expected %d %q, got %d %q", test.urlPath, test.status, test.location, res.StatusCode(), location(res))
+		}
+	}
+}
+
+func TestTrailingSlashRedirects(t *testing.T) {
+	serveFiles(t, map[string]string{"about.html": "<h1>About</h1>", "docs/index.html": "<h1>Docs</h1>"})
+
+	tests := []struct {
+		mode     string
+		urlPath  string
+		status   int
+		location string
+	}{
+		{"ignore", "/docs", fasthttp.StatusOK, ""},
+		{"ignore", "/docs/", fasthttp.StatusOK, ""},
+		{"always", "/docs", fasthttp.StatusMovedPermanently, "/docs/"},
+		{"always", "/docs?page=2", fasthttp.StatusMovedPermanently, "/docs/?page=2"},
+		{"always", "/docs/", fasthttp.StatusOK, ""},
+		{"never", "/docs/", fasthttp.StatusMovedPermanently, "/docs"},
+		{"never", "/about.html/", fasthttp.StatusMovedPermanently, "/about.html"},
+		{"never", "/docs", fasthttp.StatusOK, ""},
+		{"redirect", "/docs", fasthttp.StatusMovedPermanently, "/docs/"},
+		{"redirect", "/about.html/", fasthttp.StatusMovedPermanently, "/about.html"},
+		{"redirect", "/about.html", fasthttp.StatusOK, ""},
+		{"redirect", "/missing/", fasthttp.StatusNotFound, ""},
+	}
+	previous := trailingSlash
+	t.Cleanup(func() { trailingSlash = previous })
+	for _, test := range tests {
+		trailingSlash = test.mode
+		res := request(test.urlPath, nil)
+		if res.StatusCode() != test.status || location(res) != test.location {
+			t.Errorf("%s %s: expected %d %q, got %d %q", test.mode, test.urlPath, test.status, test.location, res.StatusCode(), location(res))
+		}
+	}
+}